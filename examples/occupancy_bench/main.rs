@@ -0,0 +1,381 @@
+use std::process::Command;
+
+use bevy::{
+    app::AppExit,
+    diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    prelude::*,
+    window::PresentMode,
+};
+use clap::Parser;
+
+use bevylder::{Voxel, VoxelBundle, VoxelData, VoxelPlugin, VoxelSettings};
+
+/// Orbits a large, mostly empty grid to compare traversal with and without
+/// skipping empty bricks. Run with `--heatmap` to see how many steps each
+/// ray takes, from blue to red, or with `--compare` to print the frame times
+/// of both modes side by side, along with the steps rays take through the
+/// same grid when traversed on the CPU.
+#[derive(Parser)]
+#[clap()]
+struct Context {
+    /// Edge of the grid in cells
+    #[clap(long, default_value_t = 256)]
+    size: u32,
+    /// Number of spheres scattered in the grid
+    #[clap(long, default_value_t = 48)]
+    spheres: u32,
+    /// Step through every cell like before the occupancy pyramid
+    #[clap(long)]
+    no_skipping: bool,
+    /// Color voxels by traversal steps instead of shading them
+    #[clap(long)]
+    heatmap: bool,
+    /// Print the average frame time over this many frames and exit
+    #[clap(long)]
+    frames: Option<u32>,
+    /// Measure with and without skipping and print both frame times
+    #[clap(long)]
+    compare: bool,
+}
+
+/// Frames rendered before measuring, while pipelines compile
+const WARMUP_FRAMES: u32 = 120;
+
+/// Rays cast along each side of the view when counting steps on the CPU
+const STEP_RAYS: u32 = 128;
+
+/// Scale of the grid entity, it spans this many units
+const GRID_SCALE: f32 = 4.0;
+
+fn main() {
+    let context = Context::parse();
+    if context.compare {
+        compare(&context);
+        return;
+    }
+
+    App::new()
+        .insert_resource(Msaa { samples: 1 })
+        .insert_resource(WindowDescriptor {
+            title: "bevylder occupancy bench".to_string(),
+            // Vsync would cap measured frame times
+            present_mode: if context.frames.is_some() {
+                PresentMode::Immediate
+            } else {
+                PresentMode::Fifo
+            },
+            ..Default::default()
+        })
+        .add_plugin(LogDiagnosticsPlugin::default())
+        .add_plugin(FrameTimeDiagnosticsPlugin::default())
+        .add_plugins(DefaultPlugins)
+        .insert_resource(VoxelSettings {
+            empty_space_skipping: !context.no_skipping,
+            iteration_heatmap: context.heatmap,
+            ..default()
+        })
+        .add_plugin(VoxelPlugin)
+        .insert_resource(context)
+        .add_system(bevy::window::close_on_esc)
+        .add_startup_system(setup)
+        .add_system(orbit_camera)
+        .add_system(measure_frames)
+        .run();
+}
+
+/// Runs the bench once per mode and prints their average frame times
+fn compare(context: &Context) {
+    let frames = context.frames.unwrap_or(600);
+    let measure = |skipping: bool| {
+        let mut command = Command::new(std::env::current_exe().unwrap());
+        command
+            .arg(format!("--size={}", context.size))
+            .arg(format!("--spheres={}", context.spheres))
+            .arg(format!("--frames={}", frames));
+        if !skipping {
+            command.arg("--no-skipping");
+        }
+        let output = command.output().expect("failed to run the bench");
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix("frame_ms ")?.parse::<f64>().ok())
+            .expect("bench printed no frame time")
+    };
+    let skipping = measure(true);
+    let stepping = measure(false);
+    let (stepping_steps, skipping_steps) = cpu_steps(context);
+
+    println!(
+        "{}³ grid, {} spheres, {} frames",
+        context.size, context.spheres, frames
+    );
+    println!(
+        "{:>14} {:>12} {:>12} {:>8}",
+        "", "stepping", "skipping", "speedup"
+    );
+    println!(
+        "{:>14} {:>9.3} ms {:>9.3} ms {:>7.2}x",
+        "frame time",
+        stepping,
+        skipping,
+        stepping / skipping
+    );
+    println!(
+        "{:>14} {:>12.1} {:>12.1} {:>7.2}x",
+        "steps per ray",
+        stepping_steps,
+        skipping_steps,
+        stepping_steps / skipping_steps
+    );
+}
+
+/// Average traversal steps of rays from the first camera position through
+/// the bench grid, stepping through every cell and skipping empty bricks
+fn cpu_steps(context: &Context) -> (f64, f64) {
+    let data = sparse_grid(context.size, context.spheres);
+    let bricks = Bricks::new(&data);
+    let camera = camera_transform(0.0);
+    // Matches the vertical field of view of the default projection
+    let half_fov = (std::f32::consts::FRAC_PI_4 * 0.5).tan();
+
+    // Grid space, where cells are one unit wide
+    let size = data.size().as_vec3();
+    let to_grid = size.max_element() / GRID_SCALE;
+    let origin = camera.translation * to_grid + size * 0.5;
+
+    let (mut stepping, mut skipping, mut rays) = (0, 0, 0);
+    for j in 0..STEP_RAYS {
+        for i in 0..STEP_RAYS {
+            let ndc = (Vec2::new(i as f32, j as f32) + 0.5) / STEP_RAYS as f32 * 2.0 - 1.0;
+            let direction = camera.forward()
+                + camera.right() * ndc.x * half_fov
+                + camera.up() * ndc.y * half_fov;
+            let direction = direction.normalize();
+            if let Some(steps) = traversal_steps(&data, None, origin, direction) {
+                stepping += steps;
+                skipping += traversal_steps(&data, Some(&bricks), origin, direction).unwrap_or(0);
+                rays += 1;
+            }
+        }
+    }
+    let rays = rays.max(1) as f64;
+    (stepping as f64 / rays, skipping as f64 / rays)
+}
+
+/// Occupancy of the 2³ and 4³ bricks of a grid, like the pyramid
+/// `voxel.wgsl` reads
+struct Bricks {
+    /// Edge, bricks along each axis and occupancy of each level, coarsest first
+    levels: Vec<(i32, IVec3, Vec<bool>)>,
+}
+
+impl Bricks {
+    fn new(data: &VoxelData) -> Self {
+        let size = data.size().as_ivec3();
+        let levels = [4, 2]
+            .into_iter()
+            .map(|brick| {
+                let count = (size + brick - 1) / brick;
+                let mut occupied = vec![false; (count.x * count.y * count.z) as usize];
+                for (position, _) in data.iter() {
+                    let at = position.as_ivec3() / brick;
+                    occupied[(at.x + at.z * count.x + at.y * count.x * count.z) as usize] = true;
+                }
+                (brick, count, occupied)
+            })
+            .collect();
+        Self { levels }
+    }
+
+    /// Edge of the largest empty brick around `cell`, one if it is occupied
+    fn empty_brick(&self, cell: IVec3) -> i32 {
+        self.levels
+            .iter()
+            .find(|(brick, count, occupied)| {
+                let at = cell / *brick;
+                !occupied[(at.x + at.z * count.x + at.y * count.x * count.z) as usize]
+            })
+            .map_or(1, |(brick, ..)| *brick)
+    }
+}
+
+/// Steps a ray from `origin` along `direction` in grid space takes through
+/// the occupied box of `data`, the way `voxel.wgsl` walks it, `None` if it
+/// misses the box
+fn traversal_steps(
+    data: &VoxelData,
+    bricks: Option<&Bricks>,
+    origin: Vec3,
+    direction: Vec3,
+) -> Option<u32> {
+    let (min, max) = data.bounds()?;
+    let inverse = direction.recip();
+    let t0 = (min.as_vec3() - origin) * inverse;
+    let t1 = (max.as_vec3() - origin) * inverse;
+    let t_enter = t0.min(t1).max_element().max(0.0);
+    if t_enter > t0.max(t1).min_element() {
+        return None;
+    }
+
+    let (min, max) = (min.as_ivec3(), max.as_ivec3());
+    let start = origin + direction * t_enter;
+    let mut cell = start.floor().as_ivec3().clamp(min, max - 1);
+    let step = IVec3::from(direction.to_array().map(|axis| {
+        if axis > 0.0 {
+            1
+        } else if axis < 0.0 {
+            -1
+        } else {
+            0
+        }
+    }));
+    let t_delta = inverse.abs();
+    // Where the ray leaves the current cell through each axis
+    let mut t_max = Vec3::ZERO;
+    for axis in 0..3 {
+        t_max[axis] = if step[axis] == 0 {
+            f32::INFINITY
+        } else {
+            let boundary = cell[axis] + i32::from(step[axis] > 0);
+            (boundary as f32 - start[axis]) * inverse[axis]
+        };
+    }
+
+    let mut steps = 0;
+    loop {
+        steps += 1;
+        let brick = bricks.map_or(1, |bricks| bricks.empty_brick(cell));
+        if brick > 1 {
+            // Jump to the first cell past the brick, like the shader
+            let brick_min = cell / brick * brick;
+            let brick_max = brick_min + brick - 1;
+            let mut t_exit = Vec3::splat(f32::INFINITY);
+            let mut remaining = IVec3::ZERO;
+            for axis in 0..3 {
+                if step[axis] != 0 {
+                    remaining[axis] = if step[axis] > 0 {
+                        brick_max[axis] - cell[axis]
+                    } else {
+                        cell[axis] - brick_min[axis]
+                    };
+                    t_exit[axis] = t_max[axis] + remaining[axis] as f32 * t_delta[axis];
+                }
+            }
+            let axis = (0..3)
+                .min_by(|&a, &b| t_exit[a].total_cmp(&t_exit[b]))
+                .unwrap();
+            let t_jump = t_exit[axis];
+            for other in 0..3 {
+                let crossed = if step[other] == 0 {
+                    0
+                } else if other == axis {
+                    remaining[axis] + 1
+                } else if t_max[other] < t_jump {
+                    ((t_jump - t_max[other]) / t_delta[other]).floor() as i32 + 1
+                } else {
+                    0
+                };
+                cell[other] += step[other] * crossed;
+                t_max[other] += crossed as f32 * t_delta[other];
+            }
+        } else {
+            let occupied = data
+                .index(cell.as_uvec3())
+                .map_or(false, |index| data.cells().is_occupied(index));
+            if occupied {
+                return Some(steps);
+            }
+            let axis = (0..3)
+                .min_by(|&a, &b| t_max[a].total_cmp(&t_max[b]))
+                .unwrap();
+            cell[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+        }
+        if cell.cmplt(min).any() || cell.cmpge(max).any() {
+            return Some(steps);
+        }
+    }
+}
+
+/// With `--frames`, prints the average frame time after warming up and exits
+fn measure_frames(
+    context: Res<Context>,
+    time: Res<Time>,
+    mut frame: Local<u32>,
+    mut total: Local<f64>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let frames = match context.frames {
+        Some(frames) => frames.max(1),
+        None => return,
+    };
+    *frame += 1;
+    if *frame <= WARMUP_FRAMES {
+        return;
+    }
+    *total += time.delta_seconds_f64();
+    if *frame == WARMUP_FRAMES + frames {
+        println!("frame_ms {}", *total * 1000.0 / frames as f64);
+        exit.send(AppExit);
+    }
+}
+
+/// Spheres of random colors at random places, leaving most cells empty
+fn sparse_grid(size: u32, spheres: u32) -> VoxelData {
+    let mut data = VoxelData::with_palette(UVec3::splat(size), vec![0]);
+    let mut seed = 0x2545_f491_u32;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as f32 / u32::MAX as f32
+    };
+
+    for _ in 0..spheres {
+        let center = Vec3::new(random(), random(), random()) * size as f32;
+        let radius = (0.02 + 0.06 * random()) * size as f32;
+        let color = 0xFF00_0000 | (random() * 0xFF_FFFF as f32) as u32;
+        let min = (center - radius).max(Vec3::ZERO).as_uvec3();
+        let max = (center + radius).min(Vec3::splat(size as f32)).as_uvec3();
+        for y in min.y..max.y {
+            for z in min.z..max.z {
+                for x in min.x..max.x {
+                    let position = UVec3::new(x, y, z);
+                    if (position.as_vec3() + 0.5).distance(center) < radius {
+                        data.set(position, color).unwrap();
+                    }
+                }
+            }
+        }
+    }
+    data
+}
+
+fn setup(mut commands: Commands, mut voxel_data: ResMut<Assets<VoxelData>>, context: Res<Context>) {
+    commands.spawn_bundle(VoxelBundle {
+        voxel: Voxel {
+            data: voxel_data.add(sparse_grid(context.size, context.spheres)),
+        },
+        transform: Transform::from_scale(Vec3::splat(GRID_SCALE)),
+        ..default()
+    });
+
+    commands.spawn_bundle(DirectionalLightBundle {
+        transform: Transform::from_xyz(1.0, 2.0, 0.5).looking_at(Vec3::ZERO, Vec3::Y),
+        ..default()
+    });
+    commands.spawn_bundle(Camera3dBundle::default());
+}
+
+fn orbit_camera(time: Res<Time>, mut cameras: Query<&mut Transform, With<Camera3d>>) {
+    let camera = camera_transform(time.seconds_since_startup() as f32);
+    for mut transform in cameras.iter_mut() {
+        *transform = camera;
+    }
+}
+
+/// Camera orbiting the grid, `seconds` after startup
+fn camera_transform(seconds: f32) -> Transform {
+    let angle = seconds * 0.2;
+    Transform::from_xyz(6.0 * angle.cos(), 2.5, 6.0 * angle.sin()).looking_at(Vec3::ZERO, Vec3::Y)
+}
@@ -66,7 +66,10 @@ fn setup(
             for z in -num..=num {
                 commands.spawn_bundle(VoxelBundle {
                     voxel: Voxel {
-                        data: voxel_data.add(VoxelData(test_model::TEST_MODEL_DUCK)),
+                        data: voxel_data.add(VoxelData::from_data(
+                            UVec3::splat(16),
+                            test_model::TEST_MODEL_DUCK.to_vec(),
+                        )),
                     },
                     transform: Transform::from_xyz(2.0 * x as f32, 0.0, 2.0 * z as f32),
                     ..default()
@@ -128,7 +131,10 @@ fn setup(
     } else {
         commands.spawn_bundle(VoxelBundle {
             voxel: Voxel {
-                data: voxel_data.add(VoxelData(test_model::TEST_MODEL_DUCK)),
+                data: voxel_data.add(VoxelData::from_data(
+                    UVec3::splat(16),
+                    test_model::TEST_MODEL_DUCK.to_vec(),
+                )),
             },
             transform: Transform::from_xyz(0.0, 0.0, 0.0),
             ..default()
@@ -136,7 +142,10 @@ fn setup(
 
         commands.spawn_bundle(VoxelBundle {
             voxel: Voxel {
-                data: voxel_data.add(VoxelData(test_model::TEST_MODEL_DUCK)),
+                data: voxel_data.add(VoxelData::from_data(
+                    UVec3::splat(16),
+                    test_model::TEST_MODEL_DUCK.to_vec(),
+                )),
             },
             transform: Transform::from_xyz(2.0, 0.0, 0.0),
             ..default()
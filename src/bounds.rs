@@ -0,0 +1,76 @@
+use bevy::{prelude::*, render::primitives::Aabb, utils::HashSet};
+
+use super::{
+    sparse::{SparseVoxel, SparseVoxelData},
+    voxel::{Voxel, VoxelData},
+};
+
+/// Object space box of the `bounds` occupied in a grid of `size`, see the
+/// proxy mapping in `voxel.wgsl`
+fn occupied_aabb(size: UVec3, bounds: Option<(UVec3, UVec3)>) -> Aabb {
+    let size = size.as_vec3();
+    let max_size = size.max_element();
+    let to_local = |cell: UVec3| (cell.as_vec3() - size * 0.5) / max_size;
+    match bounds {
+        Some((min, max)) => Aabb::from_min_max(to_local(min), to_local(max)),
+        None => Aabb::from_min_max(Vec3::ZERO, Vec3::ZERO),
+    }
+}
+
+/// Fits the [`Aabb`] of voxel entities to the occupied cells of their data,
+/// so they are culled like meshes
+pub(crate) fn update_voxel_aabbs(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<VoxelData>>,
+    assets: Res<Assets<VoxelData>>,
+    voxels: Query<(Entity, &Voxel, ChangeTrackers<Voxel>)>,
+) {
+    let changed: HashSet<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect();
+
+    for (entity, voxel, tracker) in voxels.iter() {
+        if !tracker.is_changed() && !changed.contains(&voxel.data) {
+            continue;
+        }
+        // Bounds are cached by the asset, so entities sharing it don't scan
+        // it again
+        if let Some(data) = assets.get(&voxel.data) {
+            commands
+                .entity(entity)
+                .insert(occupied_aabb(data.size(), data.bounds()));
+        }
+    }
+}
+
+/// Same as [`update_voxel_aabbs`] for sparse voxels, whose bounds are known
+/// since their tree was built
+pub(crate) fn update_sparse_voxel_aabbs(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<SparseVoxelData>>,
+    assets: Res<Assets<SparseVoxelData>>,
+    voxels: Query<(Entity, &SparseVoxel, ChangeTrackers<SparseVoxel>)>,
+) {
+    let changed: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect();
+
+    for (entity, voxel, tracker) in voxels.iter() {
+        if !tracker.is_changed() && !changed.contains(&&voxel.data) {
+            continue;
+        }
+        if let Some(data) = assets.get(&voxel.data) {
+            commands
+                .entity(entity)
+                .insert(occupied_aabb(data.size(), data.bounds()));
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use bevy::prelude::*;
 
-use super::voxel;
+use super::{meshing::VoxelRenderMode, sparse::SparseVoxel, voxel};
 
 #[derive(Bundle, Clone)]
 pub struct VoxelBundle {
     pub voxel: voxel::Voxel,
+    pub render_mode: VoxelRenderMode,
     pub transform: Transform,
     pub global_transform: GlobalTransform,
     /// User indication of whether an entity is visible
@@ -17,6 +18,7 @@ impl Default for VoxelBundle {
     fn default() -> Self {
         Self {
             voxel: Default::default(),
+            render_mode: Default::default(),
             transform: Default::default(),
             global_transform: Default::default(),
             visibility: Default::default(),
@@ -24,3 +26,14 @@ impl Default for VoxelBundle {
         }
     }
 }
+
+#[derive(Bundle, Clone, Default)]
+pub struct SparseVoxelBundle {
+    pub sparse_voxel: SparseVoxel,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+    /// User indication of whether an entity is visible
+    pub visibility: Visibility,
+    /// Algorithmically-computed indication of whether an entity is visible and should be extracted for rendering
+    pub computed_visibility: ComputedVisibility,
+}
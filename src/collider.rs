@@ -0,0 +1,277 @@
+use bevy::prelude::*;
+
+use super::{
+    meshing::greedy_quads,
+    voxel::{Voxel, VoxelCells, VoxelData},
+    voxel_type::{VoxelTypeId, VoxelTypeRegistry},
+};
+
+/// Axis aligned box of solid cells
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColliderBox {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+}
+
+/// Triangle mesh over the surface of solid cells
+#[derive(Clone, Debug, Default)]
+pub struct ColliderTrimesh {
+    pub vertices: Vec<Vec3>,
+    /// Counter-clockwise when seen from outside
+    pub indices: Vec<[u32; 3]>,
+}
+
+/// Collision shapes of a [`VoxelData`], in the same local space as its
+/// rendered proxy, ready to be turned into colliders of a physics engine.
+///
+/// Kept up to date on entities with [`VoxelColliderSettings`].
+#[derive(Component, Clone, Debug, Default)]
+pub struct VoxelCollider {
+    /// Disjoint boxes covering every solid cell
+    pub boxes: Vec<ColliderBox>,
+    pub trimesh: Option<ColliderTrimesh>,
+}
+
+/// Asks for a [`VoxelCollider`] to be generated on a [`Voxel`] entity
+#[derive(Component, Clone, Debug, Default)]
+pub struct VoxelColliderSettings {
+    /// Also build [`VoxelCollider::trimesh`]
+    pub trimesh: bool,
+}
+
+impl VoxelCollider {
+    /// Builds the shapes of `data`, cells of types that are not
+    /// [`solid`](crate::VoxelType::solid) are left out
+    pub fn from_data(data: &VoxelData, types: &VoxelTypeRegistry, trimesh: bool) -> Self {
+        let solid = solid_cells(data, types);
+        Self {
+            boxes: collider_boxes(data.size(), &solid),
+            trimesh: trimesh.then(|| collider_trimesh(data.size(), &solid)),
+        }
+    }
+}
+
+fn solid_cells(data: &VoxelData, types: &VoxelTypeRegistry) -> Vec<bool> {
+    match data.cells() {
+        VoxelCells::Typed(ids) => ids
+            .iter()
+            .map(|&id| {
+                types
+                    .get(VoxelTypeId(id))
+                    .map_or(false, |voxel_type| voxel_type.solid)
+            })
+            .collect(),
+        cells => {
+            let size = data.size();
+            (0..(size.x * size.y * size.z) as usize)
+                .map(|index| cells.is_occupied(index))
+                .collect()
+        }
+    }
+}
+
+/// Maps grid space to the local space of the proxy, see `voxel.wgsl`
+fn to_local(size: UVec3) -> impl Fn(Vec3) -> Vec3 {
+    let max_size = size.max_element().max(1) as f32;
+    let half = size.as_vec3() * 0.5;
+    move |position| (position - half) / max_size
+}
+
+/// Greedily grows boxes along `x`, then `z`, then `y` from the first
+/// uncovered solid cell in memory order
+fn collider_boxes(size: UVec3, solid: &[bool]) -> Vec<ColliderBox> {
+    let (width, height, depth) = (size.x as usize, size.y as usize, size.z as usize);
+    let index = |x: usize, y: usize, z: usize| x + z * width + y * width * depth;
+    let to_local = to_local(size);
+    let mut covered = vec![false; solid.len()];
+    let free = |covered: &[bool], i: usize| solid[i] && !covered[i];
+
+    let mut boxes = Vec::new();
+    for y in 0..height {
+        for z in 0..depth {
+            for x in 0..width {
+                if !free(&covered, index(x, y, z)) {
+                    continue;
+                }
+                let mut max_x = x + 1;
+                while max_x < width && free(&covered, index(max_x, y, z)) {
+                    max_x += 1;
+                }
+                let mut max_z = z + 1;
+                while max_z < depth && (x..max_x).all(|x| free(&covered, index(x, y, max_z))) {
+                    max_z += 1;
+                }
+                let mut max_y = y + 1;
+                while max_y < height
+                    && (z..max_z).all(|z| (x..max_x).all(|x| free(&covered, index(x, max_y, z))))
+                {
+                    max_y += 1;
+                }
+                for y in y..max_y {
+                    for z in z..max_z {
+                        for x in x..max_x {
+                            covered[index(x, y, z)] = true;
+                        }
+                    }
+                }
+
+                let min = to_local(UVec3::new(x as u32, y as u32, z as u32).as_vec3());
+                let max = to_local(UVec3::new(max_x as u32, max_y as u32, max_z as u32).as_vec3());
+                boxes.push(ColliderBox {
+                    center: (min + max) * 0.5,
+                    half_extents: (max - min) * 0.5,
+                });
+            }
+        }
+    }
+    boxes
+}
+
+fn collider_trimesh(size: UVec3, solid: &[bool]) -> ColliderTrimesh {
+    let to_local = to_local(size);
+    let mut trimesh = ColliderTrimesh::default();
+    greedy_quads(
+        size,
+        |index| if solid[index] { Some(0) } else { None },
+        |_| false,
+        |quad| {
+            let start = trimesh.vertices.len() as u32;
+            trimesh.vertices.extend(
+                quad.vertices()
+                    .into_iter()
+                    .map(|(position, _)| to_local(position)),
+            );
+            trimesh
+                .indices
+                .extend([[start, start + 1, start + 2], [start, start + 2, start + 3]]);
+        },
+    );
+    trimesh
+}
+
+/// Rebuilds the [`VoxelCollider`] of entities with [`VoxelColliderSettings`]
+/// when their asset, the type registry or the settings change
+#[allow(clippy::type_complexity)]
+pub(crate) fn update_voxel_colliders(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<VoxelData>>,
+    assets: Res<Assets<VoxelData>>,
+    types: Res<VoxelTypeRegistry>,
+    voxels: Query<(
+        Entity,
+        &Voxel,
+        &VoxelColliderSettings,
+        Option<&VoxelCollider>,
+        ChangeTrackers<Voxel>,
+        ChangeTrackers<VoxelColliderSettings>,
+    )>,
+) {
+    let changed: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect();
+
+    for (entity, voxel, settings, collider, voxel_tracker, settings_tracker) in voxels.iter() {
+        let outdated = collider.is_none()
+            || voxel_tracker.is_changed()
+            || settings_tracker.is_changed()
+            || types.is_changed()
+            || changed.contains(&&voxel.data);
+        if !outdated {
+            continue;
+        }
+        // Retried every frame until the asset is loaded
+        if let Some(data) = assets.get(&voxel.data) {
+            commands.entity(entity).insert(VoxelCollider::from_data(
+                data,
+                &types,
+                settings.trimesh,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{collider_boxes, ColliderBox, VoxelCollider};
+    use crate::{VoxelData, VoxelType, VoxelTypeRegistry};
+
+    /// Cells covered by `boxes` in a grid of `size`
+    fn volume(size: UVec3, boxes: &[ColliderBox]) -> f32 {
+        let cells = boxes
+            .iter()
+            .map(|collider| {
+                (collider.half_extents * 2.0)
+                    .to_array()
+                    .iter()
+                    .product::<f32>()
+            })
+            .sum::<f32>();
+        cells * (size.max_element() as f32).powi(3)
+    }
+
+    #[test]
+    fn merges_full_grids_into_one_box() {
+        let size = UVec3::new(4, 2, 2);
+        let boxes = collider_boxes(size, &[true; 16]);
+        assert_eq!(
+            boxes,
+            [ColliderBox {
+                center: Vec3::ZERO,
+                half_extents: Vec3::new(0.5, 0.25, 0.25),
+            }]
+        );
+    }
+
+    #[test]
+    fn covers_every_solid_cell_once() {
+        // An L in the bottom layer and a single cell above its corner
+        let size = UVec3::new(2, 2, 2);
+        let solid = [true, true, true, false, true, false, false, false];
+        let boxes = collider_boxes(size, &solid);
+        assert_eq!(boxes.len(), 3);
+        assert_eq!(volume(size, &boxes), 4.0);
+        assert_eq!(
+            boxes[0],
+            ColliderBox {
+                center: Vec3::new(0.0, -0.25, -0.25),
+                half_extents: Vec3::new(0.5, 0.25, 0.25),
+            }
+        );
+    }
+
+    #[test]
+    fn leaves_out_cells_that_are_not_solid() {
+        let mut types = VoxelTypeRegistry::default();
+        let stone = types.register(VoxelType {
+            name: "stone".into(),
+            ..default()
+        });
+        let water = types.register(VoxelType {
+            name: "water".into(),
+            solid: false,
+            ..default()
+        });
+        let size = UVec3::new(3, 1, 1);
+        let mut data = VoxelData::with_types(size);
+        data.set_type(UVec3::new(0, 0, 0), stone).unwrap();
+        data.set_type(UVec3::new(1, 0, 0), water).unwrap();
+        data.set_type(UVec3::new(2, 0, 0), stone).unwrap();
+        let collider = VoxelCollider::from_data(&data, &types, false);
+        assert_eq!(collider.boxes.len(), 2);
+        assert_eq!(volume(size, &collider.boxes), 2.0);
+
+        // Transparent cells of color grids are empty
+        let mut data = VoxelData::new(size);
+        data.set(UVec3::new(0, 0, 0), 0xFFFF_FFFF).unwrap();
+        data.set(UVec3::new(1, 0, 0), 0x00FF_FFFF).unwrap();
+        let collider = VoxelCollider::from_data(&data, &types, false);
+        assert_eq!(collider.boxes.len(), 1);
+        assert_eq!(volume(size, &collider.boxes), 1.0);
+    }
+}
@@ -0,0 +1,347 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use super::{
+    voxel::{Voxel, VoxelCells, VoxelData},
+    voxel_type::{VoxelTypeId, VoxelTypeRegistry},
+};
+
+/// Gap kept between a box and the cells it slid against, in world units
+const SKIN: f32 = 0.001;
+
+/// Overlap with a cell below which a box is still considered touching it,
+/// in world units. Deeper overlaps are ignored so boxes stuck inside can move out.
+const MAX_PENETRATION: f32 = 0.01;
+
+/// First solid cell met by a swept box
+#[derive(Clone, Debug)]
+pub struct VoxelContact {
+    pub entity: Entity,
+    /// Cell that was hit, in the grid of the entity's [`VoxelData`]
+    pub position: UVec3,
+    /// World space normal of the face that was hit
+    pub normal: Vec3,
+    /// Fraction of the motion done before the contact, in `0.0..=1.0`
+    pub time_of_impact: f32,
+}
+
+/// Outcome of [`VoxelCollision::move_and_slide`]
+#[derive(Clone, Debug, Default)]
+pub struct VoxelSlide {
+    /// Motion the box can do, slid along every contact
+    pub motion: Vec3,
+    pub contacts: Vec<VoxelContact>,
+}
+
+/// Sweeps axis aligned boxes through the solid cells of every [`Voxel`]
+/// entity, including the chunks of a [`VoxelWorld`](crate::VoxelWorld).
+///
+/// Cells of types that are not [`solid`](crate::VoxelType::solid) are passed
+/// through. Boxes are tested in the grid of each entity, rotated entities use
+/// the grid aligned box around the rotated one.
+#[derive(SystemParam)]
+pub struct VoxelCollision<'w, 's> {
+    assets: Res<'w, Assets<VoxelData>>,
+    types: Res<'w, VoxelTypeRegistry>,
+    voxels: Query<'w, 's, (Entity, &'static Voxel, &'static GlobalTransform)>,
+}
+
+impl<'w, 's> VoxelCollision<'w, 's> {
+    /// First contact of the world space box at `center` moved by `motion`
+    pub fn sweep(&self, center: Vec3, half_extents: Vec3, motion: Vec3) -> Option<VoxelContact> {
+        let end = center + motion;
+        let swept_min = center.min(end) - half_extents;
+        let swept_max = center.max(end) + half_extents;
+
+        let mut closest: Option<VoxelContact> = None;
+        for (entity, voxel, transform) in self.voxels.iter() {
+            let data = match self.assets.get(&voxel.data) {
+                Some(data) => data,
+                None => continue,
+            };
+            // Only grids whose occupied box the swept box overlaps go cell by cell
+            match world_bounds(data, transform) {
+                Some((min, max)) if swept_min.cmple(max).all() && swept_max.cmpge(min).all() => {}
+                _ => continue,
+            }
+            let contact = sweep_grid(data, &self.types, transform, center, half_extents, motion);
+            if let Some((position, normal, time_of_impact)) = contact {
+                if closest
+                    .as_ref()
+                    .map_or(true, |closest| time_of_impact < closest.time_of_impact)
+                {
+                    closest = Some(VoxelContact {
+                        entity,
+                        position,
+                        normal,
+                        time_of_impact,
+                    });
+                }
+            }
+        }
+        closest
+    }
+
+    /// Moves the box as far as `motion` goes, sliding along the faces it hits
+    /// like a character walking on terrain
+    pub fn move_and_slide(&self, center: Vec3, half_extents: Vec3, motion: Vec3) -> VoxelSlide {
+        slide(center, motion, |position, motion| {
+            self.sweep(position, half_extents, motion)
+        })
+    }
+}
+
+/// Moves a box from `center` by `motion`, finding its contacts with `sweep`
+/// and removing the motion into each of them
+fn slide(
+    center: Vec3,
+    motion: Vec3,
+    sweep: impl Fn(Vec3, Vec3) -> Option<VoxelContact>,
+) -> VoxelSlide {
+    let mut position = center;
+    let mut remaining = motion;
+    let mut contacts = Vec::new();
+    // Each contact removes the motion along one axis
+    for _ in 0..4 {
+        let contact = match sweep(position, remaining) {
+            Some(contact) => contact,
+            None => {
+                position += remaining;
+                break;
+            }
+        };
+        position += remaining * contact.time_of_impact + contact.normal * SKIN;
+        remaining *= 1.0 - contact.time_of_impact;
+        remaining -= contact.normal * remaining.dot(contact.normal);
+        contacts.push(contact);
+    }
+    VoxelSlide {
+        motion: position - center,
+        contacts,
+    }
+}
+
+/// World space box around the occupied cells of `data`, `None` if it is empty
+fn world_bounds(data: &VoxelData, transform: &GlobalTransform) -> Option<(Vec3, Vec3)> {
+    let (min, max) = data.bounds()?;
+    let size = data.size().as_vec3();
+    let max_size = size.max_element();
+    let to_object = |position: UVec3| (position.as_vec3() - size * 0.5) / max_size;
+    let (min, max) = (to_object(min), to_object(max));
+
+    let model = transform.compute_matrix();
+    let center = model.transform_point3((min + max) * 0.5);
+    let axes = Mat3::from_mat4(model);
+    let half = (max - min) * 0.5;
+    let half = axes.x_axis.abs() * half.x + axes.y_axis.abs() * half.y + axes.z_axis.abs() * half.z;
+    Some((center - half, center + half))
+}
+
+/// Sweeps a world space box through the solid cells of `data`, returns the
+/// first cell hit, the world normal of its face and the time of impact
+fn sweep_grid(
+    data: &VoxelData,
+    types: &VoxelTypeRegistry,
+    transform: &GlobalTransform,
+    center: Vec3,
+    half_extents: Vec3,
+    motion: Vec3,
+) -> Option<(UVec3, Vec3, f32)> {
+    let size = data.size();
+    if size.min_element() == 0 {
+        return None;
+    }
+
+    // Same mapping as the proxy in `voxel.wgsl`
+    let max_size = size.max_element() as f32;
+    let model = transform.compute_matrix();
+    let to_object = model.inverse();
+    let to_grid = |position: Vec3| position * max_size + size.as_vec3() * 0.5;
+    let center = to_grid(to_object.transform_point3(center));
+    let axes = Mat3::from_mat4(to_object);
+    let half_extents = (axes.x_axis.abs() * half_extents.x
+        + axes.y_axis.abs() * half_extents.y
+        + axes.z_axis.abs() * half_extents.z)
+        * max_size;
+    let motion = to_object.transform_vector3(motion) * max_size;
+    // The same world space tolerance in cells along each grid axis
+    let cell_size = Vec3::new(
+        model.x_axis.truncate().length(),
+        model.y_axis.truncate().length(),
+        model.z_axis.truncate().length(),
+    ) / max_size;
+    let max_penetration = MAX_PENETRATION / cell_size;
+
+    // Cells the box can touch on its way
+    let end = center + motion;
+    let min = (center.min(end) - half_extents).floor().max(Vec3::ZERO);
+    let max = (center.max(end) + half_extents).ceil().min(size.as_vec3());
+    if min.cmpge(max).any() {
+        return None;
+    }
+    let (min, max) = (min.as_uvec3(), max.as_uvec3());
+
+    let solid = |index: usize| match data.cells() {
+        VoxelCells::Typed(ids) => types
+            .get(VoxelTypeId(ids[index]))
+            .map_or(false, |voxel_type| voxel_type.solid),
+        cells => cells.is_occupied(index),
+    };
+
+    let mut closest: Option<(UVec3, usize, f32)> = None;
+    for y in min.y..max.y {
+        for z in min.z..max.z {
+            for x in min.x..max.x {
+                let cell = UVec3::new(x, y, z);
+                let index = (x + z * size.x + y * size.x * size.z) as usize;
+                if !solid(index) {
+                    continue;
+                }
+                // Ray from the box center against the cell grown by the box
+                let cell_min = cell.as_vec3() - half_extents;
+                let cell_max = cell.as_vec3() + 1.0 + half_extents;
+                if let Some((axis, t)) =
+                    sweep_point(center, motion, cell_min, cell_max, max_penetration)
+                {
+                    if closest.map_or(true, |(_, _, closest)| t < closest) {
+                        closest = Some((cell, axis, t));
+                    }
+                }
+            }
+        }
+    }
+
+    let (cell, axis, t) = closest?;
+    let mut normal = Vec3::ZERO;
+    normal[axis] = -motion[axis].signum();
+    // Normals transform with the inverse transpose
+    let normal = to_object.transpose().transform_vector3(normal).normalize();
+    Some((cell, normal, t))
+}
+
+/// Time in `0.0..=1.0` and axis at which a point moving by `motion` enters
+/// the box from `min` to `max`, ignoring boxes it is deeper inside than
+/// `max_penetration` along the entered axis
+fn sweep_point(
+    point: Vec3,
+    motion: Vec3,
+    min: Vec3,
+    max: Vec3,
+    max_penetration: Vec3,
+) -> Option<(usize, f32)> {
+    let mut t_enter = f32::NEG_INFINITY;
+    let mut t_exit = f32::INFINITY;
+    let mut enter_axis = 0;
+    for axis in 0..3 {
+        if motion[axis] == 0.0 {
+            // Sliding along a face is not a contact
+            if point[axis] <= min[axis] || point[axis] >= max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t0 = (min[axis] - point[axis]) / motion[axis];
+        let t1 = (max[axis] - point[axis]) / motion[axis];
+        let (near, far) = (t0.min(t1), t0.max(t1));
+        if near > t_enter {
+            t_enter = near;
+            enter_axis = axis;
+        }
+        t_exit = t_exit.min(far);
+    }
+
+    // Not moving at all
+    if !t_enter.is_finite() {
+        return None;
+    }
+    if t_enter >= t_exit || t_enter > 1.0 || t_exit <= 0.0 {
+        return None;
+    }
+    if t_enter < 0.0 && -t_enter * motion[enter_axis].abs() > max_penetration[enter_axis] {
+        return None;
+    }
+    Some((enter_axis, t_enter.max(0.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{slide, sweep_grid, world_bounds, VoxelContact, SKIN};
+    use crate::{VoxelData, VoxelTypeRegistry};
+
+    const HALF_EXTENTS: Vec3 = Vec3::splat(0.05);
+
+    /// 4³ grid with a floor one cell thick, its top at `y = -0.25` in the
+    /// world and cells a quarter unit wide
+    fn floor() -> VoxelData {
+        let mut data = VoxelData::new(UVec3::splat(4));
+        data.fill_box(UVec3::ZERO, UVec3::new(4, 1, 4), 0xFFFF_FFFF)
+            .unwrap();
+        data
+    }
+
+    fn sweep(data: &VoxelData, center: Vec3, motion: Vec3) -> Option<VoxelContact> {
+        let types = VoxelTypeRegistry::default();
+        let transform = GlobalTransform::identity();
+        sweep_grid(data, &types, &transform, center, HALF_EXTENTS, motion).map(
+            |(position, normal, time_of_impact)| VoxelContact {
+                entity: Entity::from_raw(0),
+                position,
+                normal,
+                time_of_impact,
+            },
+        )
+    }
+
+    #[test]
+    fn sweeps_into_the_first_solid_cell() {
+        let data = floor();
+        let contact = sweep(&data, Vec3::new(0.0, 0.5, 0.0), -Vec3::Y).unwrap();
+        assert_eq!(contact.position.y, 0);
+        assert_eq!(contact.normal, Vec3::Y);
+        assert!((contact.time_of_impact - 0.7).abs() < 1e-5);
+
+        assert!(sweep(&data, Vec3::new(0.0, 0.5, 0.0), Vec3::Y).is_none());
+        assert!(sweep(&data, Vec3::new(0.0, 0.5, 0.0), -0.1 * Vec3::Y).is_none());
+    }
+
+    #[test]
+    fn slides_along_contacts() {
+        let data = floor();
+        let center = Vec3::new(0.0, 0.5, 0.0);
+        let result = slide(center, Vec3::new(0.5, -1.0, 0.0), |position, motion| {
+            sweep(&data, position, motion)
+        });
+        assert_eq!(result.contacts.len(), 1);
+        let expected = Vec3::new(0.5, -0.7 + SKIN, 0.0);
+        assert!(
+            (result.motion - expected).length() < 1e-5,
+            "{}",
+            result.motion
+        );
+    }
+
+    #[test]
+    fn lets_stuck_boxes_move_out() {
+        let data = floor();
+        // Barely touching the floor is still a contact
+        let touching = Vec3::new(0.0, -0.2 - 0.005, 0.0);
+        let contact = sweep(&data, touching, -0.1 * Vec3::Y).unwrap();
+        assert_eq!(contact.time_of_impact, 0.0);
+        // Boxes deep inside move freely, out or along the floor
+        let stuck = Vec3::new(0.0, -0.3, 0.0);
+        assert!(sweep(&data, stuck, 0.2 * Vec3::Y).is_none());
+        assert!(sweep(&data, stuck, 0.1 * Vec3::X).is_none());
+    }
+
+    #[test]
+    fn bounds_occupied_cells_in_the_world() {
+        let data = floor();
+        let transform =
+            GlobalTransform::from(Transform::from_xyz(10.0, 0.0, 0.0).with_scale(Vec3::splat(2.0)));
+        let (min, max) = world_bounds(&data, &transform).unwrap();
+        assert_eq!(min, Vec3::new(9.0, -1.0, -1.0));
+        assert_eq!(max, Vec3::new(11.0, -0.5, 1.0));
+        assert!(world_bounds(&VoxelData::new(UVec3::ONE), &transform).is_none());
+    }
+}
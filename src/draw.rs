@@ -3,7 +3,7 @@ use bevy::{
         lifetimeless::{Read, SQuery, SRes},
         SystemParamItem,
     },
-    pbr::{SetMeshBindGroup, SetMeshViewBindGroup},
+    pbr::{SetMeshViewBindGroup, SetShadowViewBindGroup},
     prelude::*,
     render::{
         mesh::GpuBufferInfo,
@@ -14,54 +14,118 @@ use bevy::{
     },
 };
 
-use super::voxel_mesh::VoxelMesh;
-
-use super::voxel;
+use super::{
+    instance::{VoxelBatch, VoxelInstances},
+    sparse::{SparseVoxel, SparseVoxelData},
+    storage::VoxelStorage,
+    voxel_mesh::VoxelMesh,
+};
 
 pub(crate) type DrawVoxels = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
-    SetMeshBindGroup<1>,
-    SetVoxelBindGroup<2>,
-    DrawVoxel,
+    SetVoxelStorageBindGroup<1>,
+    DrawVoxelBatch,
+);
+
+pub(crate) type DrawVoxelShadows = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetVoxelStorageBindGroup<1>,
+    DrawVoxelBatch,
 );
 
-pub(crate) struct SetVoxelBindGroup<const I: usize>;
+pub(crate) type DrawSparseVoxels = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetSparseVoxelBindGroup<1>,
+    DrawVoxelBatch,
+);
 
-impl<const I: usize> EntityRenderCommand for SetVoxelBindGroup<I> {
+pub(crate) type DrawSparseVoxelShadows = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetSparseVoxelBindGroup<1>,
+    DrawVoxelBatch,
+);
+
+pub(crate) struct SetVoxelStorageBindGroup<const I: usize>;
+
+impl<const I: usize> EntityRenderCommand for SetVoxelStorageBindGroup<I> {
+    type Param = SRes<VoxelStorage>;
+
+    fn render<'w>(
+        _view: Entity,
+        _item: Entity,
+        storage: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        if let Some(bind_group) = &storage.into_inner().bind_group {
+            pass.set_bind_group(I, bind_group, &[]);
+            RenderCommandResult::Success
+        } else {
+            RenderCommandResult::Failure
+        }
+    }
+}
+
+/// Binds the tree of the [`SparseVoxel`] drawn by a batch
+pub(crate) struct SetSparseVoxelBindGroup<const I: usize>;
+
+impl<const I: usize> EntityRenderCommand for SetSparseVoxelBindGroup<I> {
     type Param = (
-        SQuery<Read<voxel::Voxel>>,
-        SRes<RenderAssets<voxel::VoxelData>>,
+        SRes<RenderAssets<SparseVoxelData>>,
+        SQuery<Read<SparseVoxel>>,
     );
 
     fn render<'w>(
         _view: Entity,
         item: Entity,
-        (voxels, voxel_data): SystemParamItem<'w, '_, Self::Param>,
+        (sparse_data, voxels): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let voxel = voxels.get_inner(item).unwrap();
-        let voxel_meta = voxel_data.into_inner().get(&voxel.data).unwrap();
-
-        pass.set_bind_group(I, &voxel_meta.bind_group, &[]);
-
-        RenderCommandResult::Success
+        let data = voxels
+            .get_inner(item)
+            .ok()
+            .and_then(|voxel| sparse_data.into_inner().get(&voxel.data));
+        if let Some(data) = data {
+            pass.set_bind_group(I, &data.bind_group, &[]);
+            RenderCommandResult::Success
+        } else {
+            RenderCommandResult::Failure
+        }
     }
 }
 
-pub struct DrawVoxel;
+/// Draws every instance of a [`VoxelBatch`] with the shared proxy mesh
+pub struct DrawVoxelBatch;
 
-impl EntityRenderCommand for DrawVoxel {
-    type Param = (SRes<RenderAssets<Mesh>>, SRes<VoxelMesh>);
+impl EntityRenderCommand for DrawVoxelBatch {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SRes<VoxelMesh>,
+        SRes<VoxelInstances>,
+        SQuery<Read<VoxelBatch>>,
+    );
     #[inline]
     fn render<'w>(
         _view: Entity,
-        _item: Entity,
-        (meshes, voxel_mesh): SystemParamItem<'w, '_, Self::Param>,
+        item: Entity,
+        (meshes, voxel_mesh, instances, batches): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
+        let batch = match batches.get_inner(item) {
+            Ok(batch) => batch,
+            Err(_) => return RenderCommandResult::Failure,
+        };
+        let instance_buffer = match instances.into_inner().buffer.buffer() {
+            Some(buffer) => buffer,
+            None => return RenderCommandResult::Failure,
+        };
+
         if let Some(gpu_mesh) = meshes.into_inner().get(&voxel_mesh.mesh) {
             pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+            pass.set_vertex_buffer(1, instance_buffer.slice(..));
             match &gpu_mesh.buffer_info {
                 GpuBufferInfo::Indexed {
                     buffer,
@@ -69,10 +133,10 @@ impl EntityRenderCommand for DrawVoxel {
                     count,
                 } => {
                     pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                    pass.draw_indexed(0..*count, 0, 0..1);
+                    pass.draw_indexed(0..*count, 0, batch.instances.clone());
                 }
                 GpuBufferInfo::NonIndexed { vertex_count } => {
-                    pass.draw(0..*vertex_count, 0..1);
+                    pass.draw(0..*vertex_count, batch.instances.clone());
                 }
             }
             RenderCommandResult::Success
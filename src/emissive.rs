@@ -0,0 +1,282 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use super::{
+    voxel::{Voxel, VoxelData},
+    voxel_type::{VoxelType, VoxelTypeId, VoxelTypeRegistry},
+};
+
+/// Approximates emissive cells of a [`Voxel`] entity with point lights.
+///
+/// Emissive cells are grouped into cubes of `cluster_size` cells, each
+/// non-empty group gets a child [`PointLight`] at its center. Lights are
+/// rebuilt whenever the asset, the emissive color of a type it uses or this
+/// component changes, clusters are shared by entities drawing the same asset.
+///
+/// Only grids of voxel types give off light, cells of color and palette
+/// grids have no emissive color.
+#[derive(Component, Clone, Debug)]
+pub struct VoxelEmissiveLights {
+    /// Edge of the cube of cells merged into one light
+    pub cluster_size: u32,
+    /// Light intensity in lumens per emissive cell at full emissive strength
+    pub intensity_per_cell: f32,
+    pub range: f32,
+    pub shadows_enabled: bool,
+}
+
+impl Default for VoxelEmissiveLights {
+    fn default() -> Self {
+        Self {
+            cluster_size: 8,
+            intensity_per_cell: 20.0,
+            range: 4.0,
+            shadows_enabled: false,
+        }
+    }
+}
+
+/// Marks point lights spawned for [`VoxelEmissiveLights`]
+#[derive(Component)]
+pub struct VoxelEmissiveLight;
+
+#[derive(Default)]
+struct Cluster {
+    position: Vec3,
+    color: Vec3,
+    cells: u32,
+}
+
+/// Clusters of one asset, shared by every entity drawing it
+#[derive(Default)]
+struct AssetClusters {
+    /// Every type found in the grid, to tell which registry edits affect it
+    types: HashSet<VoxelTypeId>,
+    /// Clusters by cluster size
+    clusters: HashMap<u32, Vec<Cluster>>,
+}
+
+/// Clusters of every asset drawn with emissive lights
+#[derive(Default)]
+pub(crate) struct EmissiveClusters {
+    assets: HashMap<Handle<VoxelData>, AssetClusters>,
+    /// Emissive color of every registered type when last seen, indexed by id
+    /// minus one
+    emissive: Vec<Vec3>,
+}
+
+fn emissive(voxel_type: &VoxelType) -> Vec3 {
+    Vec4::from(voxel_type.emissive.as_linear_rgba_f32()).truncate()
+}
+
+/// Groups emissive cells into cubes of `cluster_size` cells
+fn clusters(data: &VoxelData, types: &VoxelTypeRegistry, cluster_size: u32) -> Vec<Cluster> {
+    let mut clusters = HashMap::<UVec3, Cluster>::default();
+    for (position, id) in data.iter_types() {
+        let emissive = match types.get(id) {
+            Some(voxel_type) => emissive(voxel_type),
+            None => continue,
+        };
+        if emissive.max_element() <= 0.0 {
+            continue;
+        }
+        let cluster = clusters.entry(position / cluster_size).or_default();
+        cluster.position += position.as_vec3() + 0.5;
+        cluster.color += emissive;
+        cluster.cells += 1;
+    }
+    clusters.into_values().collect()
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+pub(crate) fn update_emissive_lights(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<VoxelData>>,
+    mut cache: Local<EmissiveClusters>,
+    assets: Res<Assets<VoxelData>>,
+    types: Res<VoxelTypeRegistry>,
+    voxels: Query<(
+        Entity,
+        &Voxel,
+        ChangeTrackers<Voxel>,
+        ChangeTrackers<VoxelEmissiveLights>,
+        &VoxelEmissiveLights,
+    )>,
+    children: Query<&Children>,
+    lights: Query<(), With<VoxelEmissiveLight>>,
+    removed: RemovedComponents<VoxelEmissiveLights>,
+) {
+    let despawn_lights = |commands: &mut Commands, entity: Entity| {
+        for &child in children
+            .get(entity)
+            .iter()
+            .flat_map(|children| children.iter())
+        {
+            if lights.get(child).is_ok() {
+                commands.entity(child).despawn_recursive();
+            }
+        }
+    };
+    for entity in removed.iter() {
+        despawn_lights(&mut commands, entity);
+    }
+
+    let mut stale = HashSet::default();
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                cache.assets.remove(handle);
+                stale.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                cache.assets.remove(handle);
+            }
+        }
+    }
+
+    if types.is_changed() {
+        let emissive: Vec<_> = types.iter().map(|(_, ty)| emissive(ty)).collect();
+        let edited: HashSet<_> = (0..emissive.len())
+            .filter(|&i| cache.emissive.get(i) != Some(&emissive[i]))
+            .map(|i| VoxelTypeId(i as u16 + 1))
+            .collect();
+        cache.emissive = emissive;
+        if !edited.is_empty() {
+            cache.assets.retain(|handle, clusters| {
+                let affected = !clusters.types.is_disjoint(&edited);
+                if affected {
+                    stale.insert(handle.clone_weak());
+                }
+                !affected
+            });
+        }
+    }
+
+    for (entity, voxel, voxel_tracker, tracker, settings) in voxels.iter() {
+        if !tracker.is_changed() && !voxel_tracker.is_changed() && !stale.contains(&voxel.data) {
+            continue;
+        }
+        let data = match assets.get(&voxel.data) {
+            Some(data) => data,
+            None => continue,
+        };
+        despawn_lights(&mut commands, entity);
+
+        let cluster_size = settings.cluster_size.max(1);
+        let asset = cache
+            .assets
+            .entry(voxel.data.clone_weak())
+            .or_insert_with(|| AssetClusters {
+                types: data.iter_types().map(|(_, id)| id).collect(),
+                ..default()
+            });
+        let clusters = asset
+            .clusters
+            .entry(cluster_size)
+            .or_insert_with(|| clusters(data, &types, cluster_size));
+
+        commands.entity(entity).with_children(|parent| {
+            for cluster in clusters.iter() {
+                parent
+                    .spawn_bundle(light(cluster, settings, data.size()))
+                    .insert(VoxelEmissiveLight);
+            }
+        });
+    }
+}
+
+/// Point light standing in for the cells of `cluster` in a grid of `size`
+fn light(cluster: &Cluster, settings: &VoxelEmissiveLights, size: UVec3) -> PointLightBundle {
+    let cells = cluster.cells as f32;
+    let color = cluster.color / cells;
+    // HDR emissive colors brighten the light instead of its tint
+    let strength = color.max_element();
+    let tint = color / strength;
+    // Cells live in the unit cube the proxy mesh spans
+    let center = (cluster.position / cells - size.as_vec3() * 0.5) / size.max_element() as f32;
+    PointLightBundle {
+        point_light: PointLight {
+            color: Color::rgb_linear(tint.x, tint.y, tint.z),
+            intensity: settings.intensity_per_cell * cells * strength,
+            range: settings.range,
+            shadows_enabled: settings.shadows_enabled,
+            ..default()
+        },
+        transform: Transform::from_translation(center),
+        ..default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{clusters, light, VoxelEmissiveLights};
+    use crate::{VoxelData, VoxelType, VoxelTypeRegistry};
+
+    fn registry() -> VoxelTypeRegistry {
+        let mut types = VoxelTypeRegistry::default();
+        types.register(VoxelType {
+            name: "stone".into(),
+            ..default()
+        });
+        types.register(VoxelType {
+            name: "lamp".into(),
+            emissive: Color::rgb_linear(2.0, 1.0, 0.0),
+            ..default()
+        });
+        types
+    }
+
+    #[test]
+    fn clusters_emissive_cells() {
+        let types = registry();
+        let stone = types.id("stone").unwrap();
+        let lamp = types.id("lamp").unwrap();
+        let mut data = VoxelData::with_types(UVec3::new(8, 4, 4));
+        data.set_type(UVec3::new(0, 0, 0), lamp).unwrap();
+        data.set_type(UVec3::new(1, 1, 1), lamp).unwrap();
+        data.set_type(UVec3::new(2, 0, 0), stone).unwrap();
+        data.set_type(UVec3::new(6, 3, 3), lamp).unwrap();
+
+        let mut clusters = clusters(&data, &types, 4);
+        clusters.sort_by(|a, b| a.position.x.total_cmp(&b.position.x));
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].cells, 2);
+        assert_eq!(clusters[0].position, Vec3::new(2.0, 2.0, 2.0));
+        assert_eq!(clusters[0].color, Vec3::new(4.0, 2.0, 0.0));
+        assert_eq!(clusters[1].cells, 1);
+        assert_eq!(clusters[1].position, Vec3::new(6.5, 3.5, 3.5));
+    }
+
+    #[test]
+    fn ignores_color_grids() {
+        let mut data = VoxelData::new(UVec3::splat(2));
+        data.set(UVec3::ZERO, 0xFFFF_FFFF).unwrap();
+        assert!(clusters(&data, &registry(), 1).is_empty());
+    }
+
+    #[test]
+    fn lights_clusters() {
+        let types = registry();
+        let lamp = types.id("lamp").unwrap();
+        let mut data = VoxelData::with_types(UVec3::new(4, 2, 2));
+        data.set_type(UVec3::new(0, 0, 0), lamp).unwrap();
+        data.set_type(UVec3::new(1, 0, 0), lamp).unwrap();
+
+        let settings = VoxelEmissiveLights::default();
+        let clusters = clusters(&data, &types, 2);
+        assert_eq!(clusters.len(), 1);
+        let bundle = light(&clusters[0], &settings, data.size());
+        let light = bundle.point_light;
+        assert_eq!(light.intensity, settings.intensity_per_cell * 2.0 * 2.0);
+        assert_eq!(light.color, Color::rgb_linear(1.0, 0.5, 0.0));
+        assert_eq!(light.range, settings.range);
+        assert_eq!(
+            bundle.transform.translation,
+            Vec3::new(-0.25, -0.125, -0.125)
+        );
+    }
+}
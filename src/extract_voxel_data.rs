@@ -0,0 +1,180 @@
+use std::ops::Range;
+
+use bevy::{
+    prelude::*,
+    render::{
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+    utils::HashSet,
+};
+
+use super::{
+    pipeline,
+    storage::VoxelStorage,
+    voxel::{DirtyRegion, VoxelCells, VoxelData},
+    voxel_type::VoxelTypeRegistry,
+};
+
+pub(crate) enum VoxelDataUpload {
+    /// Stores the whole asset again
+    Full(VoxelData),
+    /// Writes edited ranges into the slot the asset already has
+    Partial {
+        /// Word offsets into the data buffer and the words to write there
+        words: Vec<(usize, Vec<u32>)>,
+        palette: Option<Vec<u32>>,
+        /// Word offsets into the occupancy pyramid and the words to write there
+        occupancy: Vec<(usize, Vec<u32>)>,
+    },
+}
+
+#[derive(Default)]
+pub(crate) struct ExtractedVoxelData {
+    extracted: Vec<(Handle<VoxelData>, VoxelDataUpload)>,
+    /// Whether the asset has see-through cells, sent after every change
+    translucent: Vec<(Handle<VoxelData>, bool)>,
+    /// Occupied box of the asset, sent after every change
+    bounds: Vec<(Handle<VoxelData>, Option<(UVec3, UVec3)>)>,
+    removed: Vec<Handle<VoxelData>>,
+}
+
+pub(crate) fn extract_voxel_data(
+    mut commands: Commands,
+    mut events: Extract<EventReader<AssetEvent<VoxelData>>>,
+    assets: Extract<Res<Assets<VoxelData>>>,
+    types: Extract<Res<VoxelTypeRegistry>>,
+    storage: Res<VoxelStorage>,
+) {
+    let mut changed = HashSet::default();
+    let mut created = HashSet::default();
+    let mut removed = Vec::new();
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } => {
+                created.insert(handle.clone_weak());
+                changed.insert(handle.clone_weak());
+            }
+            AssetEvent::Modified { handle } => {
+                changed.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                changed.remove(handle);
+                removed.push(handle.clone_weak());
+            }
+        }
+    }
+
+    let mut extracted = Vec::new();
+    let mut translucent = Vec::new();
+    let mut bounds = Vec::new();
+    for handle in changed.drain() {
+        if let Some(data) = assets.get(&handle) {
+            let dirty = data.take_dirty();
+            if dirty.rebuild || created.contains(&handle) || storage.slot(&handle).is_none() {
+                extracted.push((handle.clone_weak(), VoxelDataUpload::Full(data.clone())));
+            } else if !dirty.is_empty() {
+                extracted.push((handle.clone_weak(), partial_upload(data, dirty)));
+            }
+            // Both are kept up to date by edits instead of scanning the grid
+            translucent.push((handle.clone_weak(), data.is_translucent(&types)));
+            bounds.push((handle, data.bounds()));
+        }
+    }
+
+    // Edited types can make any typed grid translucent or opaque
+    if types.is_changed() {
+        for (id, data) in assets.iter() {
+            if matches!(data.cells(), VoxelCells::Typed(_)) {
+                translucent.push((Handle::weak(id), data.is_translucent(&types)));
+            }
+        }
+    }
+
+    commands.insert_resource(ExtractedVoxelData {
+        extracted,
+        translucent,
+        bounds,
+        removed,
+    });
+}
+
+fn partial_upload(data: &VoxelData, dirty: DirtyRegion) -> VoxelDataUpload {
+    let mut ranges: Vec<Range<usize>> = dirty
+        .cells
+        .into_iter()
+        .map(|cells| data.cells().word_range(cells))
+        .collect();
+    ranges.sort_unstable_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    VoxelDataUpload::Partial {
+        words: merged
+            .into_iter()
+            .map(|range| (range.start, data.cells().packed_words(range)))
+            .collect(),
+        palette: dirty
+            .palette
+            .then(|| data.palette().map(<[u32]>::to_vec))
+            .flatten(),
+        occupancy: dirty
+            .occupancy
+            .into_iter()
+            .map(|words| (words.start, data.occupancy_range(words)))
+            .collect(),
+    }
+}
+
+pub(crate) fn prepare_voxel_data(
+    mut extracted: ResMut<ExtractedVoxelData>,
+    mut storage: ResMut<VoxelStorage>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    pipeline: Res<pipeline::VoxelPipeline>,
+    types: Res<VoxelTypeRegistry>,
+) {
+    if types.is_changed() {
+        storage.write_types(types.gpu_table());
+    }
+
+    for removed in std::mem::take(&mut extracted.removed) {
+        storage.remove(&removed);
+    }
+
+    for (handle, upload) in std::mem::take(&mut extracted.extracted) {
+        match upload {
+            VoxelDataUpload::Full(data) => storage.insert(handle, &data),
+            VoxelDataUpload::Partial {
+                words,
+                palette,
+                occupancy,
+            } => {
+                for (offset, words) in words {
+                    storage.write_words(&handle, offset, &words);
+                }
+                if let Some(palette) = palette {
+                    storage.write_palette(&handle, &palette);
+                }
+                for (offset, words) in occupancy {
+                    storage.write_occupancy(&handle, offset, &words);
+                }
+            }
+        }
+    }
+
+    for (handle, translucent) in std::mem::take(&mut extracted.translucent) {
+        storage.set_translucent(&handle, translucent);
+    }
+    for (handle, bounds) in std::mem::take(&mut extracted.bounds) {
+        storage.set_bounds(&handle, bounds);
+    }
+
+    storage.upload(&render_device, &render_queue, &pipeline);
+}
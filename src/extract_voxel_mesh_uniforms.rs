@@ -1,4 +1,4 @@
-use super::voxel;
+use super::{sparse::SparseVoxel, voxel};
 
 use bevy::{
     pbr::{MeshUniform, NotShadowCaster, NotShadowReceiver},
@@ -20,21 +20,26 @@ pub fn extract_voxel_meshes(
     mut prev_caster_commands_len: Local<usize>,
     mut prev_not_caster_commands_len: Local<usize>,
     meshes_query: Extract<
-        Query<(
-            Entity,
-            &ComputedVisibility,
-            &GlobalTransform,
-            With<voxel::Voxel>,
-            Option<With<NotShadowReceiver>>,
-            Option<With<NotShadowCaster>>,
-        )>,
+        Query<
+            (
+                Entity,
+                &ComputedVisibility,
+                &GlobalTransform,
+                Option<With<NotShadowReceiver>>,
+                Option<With<NotShadowCaster>>,
+            ),
+            (
+                Or<(With<voxel::Voxel>, With<SparseVoxel>)>,
+                Without<Handle<Mesh>>,
+            ),
+        >,
     >,
 ) {
     let mut caster_commands = Vec::with_capacity(*prev_caster_commands_len);
     let mut not_caster_commands = Vec::with_capacity(*prev_not_caster_commands_len);
     let visible_meshes = meshes_query.iter().filter(|(_, vis, ..)| vis.is_visible());
 
-    for (entity, _, transform, _, not_receiver, not_caster) in visible_meshes {
+    for (entity, _, transform, not_receiver, not_caster) in visible_meshes {
         let transform = transform.compute_matrix();
         let shadow_receiver_flags = if not_receiver.is_some() {
             MeshFlags::empty().bits
@@ -0,0 +1,524 @@
+use std::sync::Arc;
+
+use bevy::{
+    prelude::*,
+    render::render_resource::TextureFormat,
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+use futures_lite::future;
+use thiserror::Error;
+
+use super::{
+    noise::Noise,
+    streaming::{ChunkLoaded, ChunkUnloaded},
+    voxel::{VoxelCells, VoxelData},
+    voxel_type::VoxelTypeId,
+    world::{VoxelWorld, CHUNK_SIZE},
+};
+
+/// Produces the cells of world chunks
+pub trait VoxelGenerator: Send + Sync + 'static {
+    /// Cells of the chunk at `coords`, a [`CHUNK_SIZE`]³ grid whose first
+    /// cell is at `coords * CHUNK_SIZE` in the world
+    fn generate(&self, coords: IVec3) -> VoxelData;
+}
+
+/// Fills the chunks a [`ChunkLoader`](crate::ChunkLoader) loads into the
+/// [`VoxelWorld`] on this entity, in the background on the
+/// [`AsyncComputeTaskPool`]
+#[derive(Component)]
+pub struct VoxelWorldGenerator {
+    generator: Arc<dyn VoxelGenerator>,
+    tasks: HashMap<IVec3, Task<VoxelData>>,
+}
+
+impl VoxelWorldGenerator {
+    pub fn new(generator: impl VoxelGenerator) -> Self {
+        Self {
+            generator: Arc::new(generator),
+            tasks: default(),
+        }
+    }
+
+    /// Whether chunks are still being generated
+    pub fn is_busy(&self) -> bool {
+        !self.tasks.is_empty()
+    }
+}
+
+/// Block types of terrain columns, from the top down
+#[derive(Clone, Debug)]
+pub struct TerrainLayers {
+    /// Topmost cell, e.g. grass
+    pub surface: VoxelTypeId,
+    /// Cells below the surface, e.g. dirt
+    pub soil: VoxelTypeId,
+    pub soil_depth: u32,
+    /// Everything deeper, e.g. stone
+    pub rock: VoxelTypeId,
+}
+
+impl TerrainLayers {
+    /// Type of a cell `depth` cells below the surface
+    pub fn at_depth(&self, depth: u32) -> VoxelTypeId {
+        match depth {
+            0 => self.surface,
+            depth if depth <= self.soil_depth => self.soil,
+            _ => self.rock,
+        }
+    }
+}
+
+/// Fills a typed chunk with columns whose topmost cell is at `height - 1`
+fn heightfield_chunk(
+    coords: IVec3,
+    layers: &TerrainLayers,
+    height_at: impl Fn(IVec2) -> i32,
+) -> VoxelData {
+    let mut data = VoxelData::with_types(UVec3::splat(CHUNK_SIZE));
+    let origin = coords * CHUNK_SIZE as i32;
+    for z in 0..CHUNK_SIZE {
+        for x in 0..CHUNK_SIZE {
+            let height = height_at(IVec2::new(origin.x + x as i32, origin.z + z as i32));
+            for y in 0..CHUNK_SIZE {
+                let depth = height - 1 - (origin.y + y as i32);
+                if depth < 0 {
+                    break;
+                }
+                let _ = data.set_type(UVec3::new(x, y, z), layers.at_depth(depth as u32));
+            }
+        }
+    }
+    data
+}
+
+/// Flat ground whose surface is at `height - 1`
+#[derive(Clone, Debug)]
+pub struct FlatGenerator {
+    pub height: i32,
+    pub layers: TerrainLayers,
+}
+
+impl VoxelGenerator for FlatGenerator {
+    fn generate(&self, coords: IVec3) -> VoxelData {
+        heightfield_chunk(coords, &self.layers, |_| self.height)
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HeightmapError {
+    #[error("heightmaps can't be read from {0:?} images")]
+    UnsupportedFormat(TextureFormat),
+}
+
+/// Ground following a grayscale image, repeated over the world
+#[derive(Clone, Debug)]
+pub struct HeightmapGenerator {
+    /// Heights in `0.0..=1.0`, row by row along `z`
+    heights: Vec<f32>,
+    size: UVec2,
+    /// Height of black pixels
+    pub base_height: i32,
+    /// Height added by white pixels
+    pub max_height: f32,
+    pub layers: TerrainLayers,
+}
+
+impl HeightmapGenerator {
+    /// Reads heights from the first channel of `image`, one pixel per cell.
+    ///
+    /// Supports 8 and 16-bit unsigned normalized channels, which includes the
+    /// `Uint` formats bevy loads 16-bit images as, and 16 and 32-bit floats.
+    pub fn from_image(
+        image: &Image,
+        max_height: f32,
+        layers: TerrainLayers,
+    ) -> Result<Self, HeightmapError> {
+        let size = image.size().as_uvec2();
+        let format = image.texture_descriptor.format;
+        let decode: fn(&[u8]) -> f32 = match format {
+            TextureFormat::R8Unorm
+            | TextureFormat::Rg8Unorm
+            | TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb => |pixel| pixel[0] as f32 / u8::MAX as f32,
+            TextureFormat::R16Unorm
+            | TextureFormat::Rg16Unorm
+            | TextureFormat::Rgba16Unorm
+            | TextureFormat::R16Uint
+            | TextureFormat::Rg16Uint
+            | TextureFormat::Rgba16Uint => {
+                |pixel| u16::from_le_bytes([pixel[0], pixel[1]]) as f32 / u16::MAX as f32
+            }
+            TextureFormat::R16Float | TextureFormat::Rg16Float | TextureFormat::Rgba16Float => {
+                |pixel| f16_to_f32(u16::from_le_bytes([pixel[0], pixel[1]]))
+            }
+            TextureFormat::R32Float | TextureFormat::Rg32Float | TextureFormat::Rgba32Float => {
+                |pixel| f32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]])
+            }
+            format => return Err(HeightmapError::UnsupportedFormat(format)),
+        };
+        let pixel_size = format.describe().block_size as usize;
+        let heights = image.data.chunks_exact(pixel_size).map(decode).collect();
+        Ok(Self {
+            heights,
+            size,
+            base_height: 0,
+            max_height,
+            layers,
+        })
+    }
+}
+
+/// Widens the bits of a half precision float
+fn f16_to_f32(bits: u16) -> f32 {
+    let exponent = (bits >> 10 & 0x1F) as u32;
+    let mantissa = (bits & 0x3FF) as u32;
+    let magnitude = match exponent {
+        // Subnormal, no implicit leading one
+        0 => mantissa as f32 / (1 << 24) as f32,
+        // Infinity or NaN
+        0x1F => f32::from_bits(0x7F80_0000 | mantissa << 13),
+        // Rebias the exponent from 15 to 127
+        _ => f32::from_bits((exponent + 112) << 23 | mantissa << 13),
+    };
+    if bits & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+impl VoxelGenerator for HeightmapGenerator {
+    fn generate(&self, coords: IVec3) -> VoxelData {
+        heightfield_chunk(coords, &self.layers, |column| {
+            let pixel = column.rem_euclid(self.size.max(UVec2::ONE).as_ivec2());
+            let height = self
+                .heights
+                .get((pixel.x + pixel.y * self.size.x as i32) as usize)
+                .copied()
+                .unwrap_or(0.0);
+            self.base_height + (height * self.max_height).round() as i32
+        })
+    }
+}
+
+/// Rolling hills from 2D noise
+#[derive(Clone, Debug)]
+pub struct NoiseHeightGenerator {
+    pub noise: Noise,
+    /// Average ground height
+    pub base_height: i32,
+    /// Largest distance of the ground from `base_height`
+    pub amplitude: f32,
+    pub layers: TerrainLayers,
+}
+
+impl VoxelGenerator for NoiseHeightGenerator {
+    fn generate(&self, coords: IVec3) -> VoxelData {
+        heightfield_chunk(coords, &self.layers, |column| {
+            let height = self.noise.sample2(column.as_vec2()) * self.amplitude;
+            self.base_height + height.round() as i32
+        })
+    }
+}
+
+/// Overhangs and floating islands from 3D noise.
+///
+/// Cells are solid where the noise scaled by `amplitude` outweighs their
+/// height above `base_height`.
+#[derive(Clone, Debug)]
+pub struct NoiseDensityGenerator {
+    pub noise: Noise,
+    pub base_height: i32,
+    pub amplitude: f32,
+    pub layers: TerrainLayers,
+}
+
+impl NoiseDensityGenerator {
+    fn is_solid(&self, position: IVec3) -> bool {
+        let density = self.noise.sample3(position.as_vec3()) * self.amplitude;
+        density + (self.base_height - position.y) as f32 > 0.0
+    }
+}
+
+impl VoxelGenerator for NoiseDensityGenerator {
+    fn generate(&self, coords: IVec3) -> VoxelData {
+        let mut data = VoxelData::with_types(UVec3::splat(CHUNK_SIZE));
+        let origin = coords * CHUNK_SIZE as i32;
+        for index in 0..CHUNK_SIZE.pow(3) as usize {
+            let cell = data.position(index);
+            let position = origin + cell.as_ivec3();
+            if !self.is_solid(position) {
+                continue;
+            }
+            // Depth is only told apart down to the rock layer
+            let depth = (1..=self.layers.soil_depth + 1)
+                .find(|&depth| !self.is_solid(position + IVec3::Y * depth as i32))
+                .map_or(self.layers.soil_depth + 1, |depth| depth - 1);
+            let _ = data.set_type(cell, self.layers.at_depth(depth));
+        }
+        data
+    }
+}
+
+/// Carves tunnels into the chunks of another generator where 3D noise is
+/// close to zero
+#[derive(Clone, Debug)]
+pub struct CaveCarver<G> {
+    pub terrain: G,
+    pub noise: Noise,
+    /// Cells with noise in `-threshold..threshold` are emptied, larger values
+    /// make wider tunnels
+    pub threshold: f32,
+}
+
+impl<G: VoxelGenerator> VoxelGenerator for CaveCarver<G> {
+    fn generate(&self, coords: IVec3) -> VoxelData {
+        let mut data = self.terrain.generate(coords);
+        let origin = coords * data.size().as_ivec3();
+        let typed = matches!(data.cells(), VoxelCells::Typed(_));
+        // Reusing a transparent entry keeps carving from growing the palette
+        let empty = data
+            .palette()
+            .and_then(|palette| palette.iter().copied().find(|color| color >> 24 == 0))
+            .unwrap_or(0);
+        let size = data.size();
+        for index in 0..(size.x * size.y * size.z) as usize {
+            if !data.cells().is_occupied(index) {
+                continue;
+            }
+            let cell = data.position(index);
+            let position = (origin + cell.as_ivec3()).as_vec3();
+            if self.noise.sample3(position).abs() >= self.threshold {
+                continue;
+            }
+            let _ = if typed {
+                data.set_type(cell, VoxelTypeId::AIR)
+            } else {
+                data.set(cell, empty)
+            };
+        }
+        data
+    }
+}
+
+/// Starts generating loaded chunks and drops the tasks of unloaded ones
+pub(crate) fn start_chunk_generation(
+    mut loaded_events: EventReader<ChunkLoaded>,
+    mut unloaded_events: EventReader<ChunkUnloaded>,
+    mut generators: Query<&mut VoxelWorldGenerator>,
+) {
+    for event in unloaded_events.iter() {
+        if let Ok(mut generator) = generators.get_mut(event.world) {
+            // Dropping a task cancels it
+            generator.tasks.remove(&event.coords);
+        }
+    }
+
+    let pool = AsyncComputeTaskPool::get();
+    for event in loaded_events.iter() {
+        if let Ok(mut generator) = generators.get_mut(event.world) {
+            let voxel_generator = generator.generator.clone();
+            let coords = event.coords;
+            let task = pool.spawn(async move { voxel_generator.generate(coords) });
+            generator.tasks.insert(coords, task);
+        }
+    }
+}
+
+/// Copies the occupied cells of `edited` over `generated`
+fn keep_edits(generated: &mut VoxelData, edited: &VoxelData) {
+    if edited.size() != generated.size() || edited.bounds().is_none() {
+        return;
+    }
+    // Only one of these yields cells, depending on the format
+    for (position, color) in edited.iter() {
+        let _ = generated.set(position, color);
+    }
+    for (position, id) in edited.iter_types() {
+        let _ = generated.set_type(position, id);
+    }
+}
+
+/// Replaces the empty chunks left by streaming with generated ones.
+///
+/// Cells set on a placeholder while its chunk was generated are kept over
+/// the generated ones. Cells emptied on it can't be told apart from
+/// untouched ones, so generation fills them.
+pub(crate) fn finish_chunk_generation(
+    mut assets: ResMut<Assets<VoxelData>>,
+    mut worlds: Query<(&mut VoxelWorld, &mut VoxelWorldGenerator)>,
+) {
+    for (mut world, mut generator) in worlds.iter_mut() {
+        if generator.tasks.is_empty() {
+            continue;
+        }
+        let mut finished = Vec::new();
+        generator.tasks.retain(
+            |coords, task| match future::block_on(future::poll_once(task)) {
+                Some(data) => {
+                    finished.push((*coords, data));
+                    false
+                }
+                None => true,
+            },
+        );
+        for (coords, mut data) in finished {
+            if data.size() != UVec3::splat(CHUNK_SIZE) {
+                error!(
+                    "generated chunk {} is a {} grid instead of {}³ cells",
+                    coords,
+                    data.size(),
+                    CHUNK_SIZE
+                );
+                continue;
+            }
+            let placeholder = match world.chunk(coords) {
+                Some(handle) => handle,
+                None => continue,
+            };
+            if let Some(edited) = assets.get(placeholder) {
+                keep_edits(&mut data, edited);
+            }
+            world.insert_chunk(coords, assets.add(data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        prelude::*,
+        render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    };
+
+    use super::{
+        f16_to_f32, CaveCarver, FlatGenerator, HeightmapError, HeightmapGenerator,
+        NoiseHeightGenerator, TerrainLayers, VoxelGenerator, CHUNK_SIZE,
+    };
+    use crate::{Noise, VoxelData, VoxelTypeId};
+
+    fn layers() -> TerrainLayers {
+        TerrainLayers {
+            surface: VoxelTypeId(1),
+            soil: VoxelTypeId(2),
+            soil_depth: 2,
+            rock: VoxelTypeId(3),
+        }
+    }
+
+    /// Height of the column at `x, z` of a chunk, `0` if it is empty
+    fn column_height(data: &VoxelData, x: u32, z: u32) -> u32 {
+        (0..CHUNK_SIZE)
+            .rev()
+            .find(|&y| data.get_type(UVec3::new(x, y, z)).unwrap() != VoxelTypeId::AIR)
+            .map_or(0, |y| y + 1)
+    }
+
+    #[test]
+    fn layers_flat_ground() {
+        let generator = FlatGenerator {
+            height: 5,
+            layers: layers(),
+        };
+        let data = generator.generate(IVec3::ZERO);
+        assert_eq!(data.size(), UVec3::splat(CHUNK_SIZE));
+        let column: Vec<_> = (0..6)
+            .map(|y| data.get_type(UVec3::new(3, y, 7)).unwrap().0)
+            .collect();
+        assert_eq!(column, [3, 3, 2, 2, 1, 0]);
+        // Chunks above the ground are empty, those below full of rock
+        assert!(generator.generate(IVec3::Y).bounds().is_none());
+        let below = generator.generate(-IVec3::Y);
+        assert!(below.iter_types().all(|(_, id)| id == VoxelTypeId(3)));
+        assert_eq!(below.iter_types().count(), CHUNK_SIZE.pow(3) as usize);
+    }
+
+    #[test]
+    fn reads_heightmaps() {
+        let image = Image::new(
+            Extent3d {
+                width: 2,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            vec![0, 255],
+            TextureFormat::R8Unorm,
+        );
+        let generator = HeightmapGenerator::from_image(&image, 10.0, layers()).unwrap();
+        let data = generator.generate(IVec3::ZERO);
+        assert_eq!(column_height(&data, 0, 0), 0);
+        assert_eq!(column_height(&data, 1, 0), 10);
+        // The image repeats
+        assert_eq!(column_height(&data, 2, 0), 0);
+        assert_eq!(column_height(&data, 3, 5), 10);
+    }
+
+    #[test]
+    fn rejects_unsupported_heightmaps() {
+        let image = Image::new(
+            Extent3d::default(),
+            TextureDimension::D2,
+            vec![0; 4],
+            TextureFormat::R32Uint,
+        );
+        assert_eq!(
+            HeightmapGenerator::from_image(&image, 10.0, layers()).unwrap_err(),
+            HeightmapError::UnsupportedFormat(TextureFormat::R32Uint)
+        );
+    }
+
+    #[test]
+    fn generates_noise_terrain_deterministically() {
+        let generator = NoiseHeightGenerator {
+            noise: Noise::default(),
+            base_height: CHUNK_SIZE as i32 / 2,
+            amplitude: 4.0,
+            layers: layers(),
+        };
+        let data = generator.generate(IVec3::ZERO);
+        assert!(data
+            .iter_types()
+            .eq(generator.generate(IVec3::ZERO).iter_types()));
+        for (x, z) in [(0, 0), (5, 9), (CHUNK_SIZE - 1, CHUNK_SIZE - 1)] {
+            let height = column_height(&data, x, z) as i32;
+            assert!((height - generator.base_height).abs() <= 4);
+        }
+    }
+
+    #[test]
+    fn carves_without_growing_palettes() {
+        struct Filled;
+        impl VoxelGenerator for Filled {
+            fn generate(&self, _: IVec3) -> VoxelData {
+                let size = UVec3::splat(CHUNK_SIZE);
+                let mut data = VoxelData::with_palette(size, vec![0x00FF_FFFF, 0xFF00_FF00]);
+                data.fill_box(UVec3::ZERO, size, 0xFF00_FF00).unwrap();
+                data
+            }
+        }
+        let carver = CaveCarver {
+            terrain: Filled,
+            noise: Noise::default(),
+            threshold: 0.5,
+        };
+        let data = carver.generate(IVec3::ZERO);
+        assert_eq!(data.palette().unwrap().len(), 2);
+        let empty = data.iter().count() < CHUNK_SIZE.pow(3) as usize;
+        assert!(empty, "no cells were carved");
+    }
+
+    #[test]
+    fn widens_half_floats() {
+        assert_eq!(f16_to_f32(0x0000), 0.0);
+        assert_eq!(f16_to_f32(0x3C00), 1.0);
+        assert_eq!(f16_to_f32(0x3800), 0.5);
+        assert_eq!(f16_to_f32(0xC000), -2.0);
+        assert_eq!(f16_to_f32(0x7BFF), 65504.0);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7C00), f32::INFINITY);
+        assert!(f16_to_f32(0x7E00).is_nan());
+    }
+}
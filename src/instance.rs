@@ -0,0 +1,207 @@
+use std::ops::Range;
+
+use bevy::{
+    pbr::MeshUniform,
+    prelude::*,
+    render::{
+        render_resource::{
+            BufferUsages, BufferVec, VertexAttribute, VertexBufferLayout, VertexFormat,
+            VertexStepMode,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    sparse::{GpuSparseVoxelData, SparseVoxel},
+    storage::VoxelStorage,
+    voxel::Voxel,
+};
+
+/// Maximum number of instances drawn by a single phase item, smaller batches
+/// keep the front to back order of opaque voxels closer to per-entity sorting
+const BATCH_SIZE: usize = 256;
+
+/// Shader location of the first instance attribute, right after the
+/// position, normal and uv of the proxy mesh
+const FIRST_INSTANCE_LOCATION: u32 = 3;
+
+/// Per-instance vertex data of a voxel entity
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub(crate) struct VoxelInstance {
+    model: [[f32; 4]; 4],
+    inverse_model: [[f32; 4]; 4],
+    /// Grid size and cell format
+    layout: [u32; 4],
+    /// Offsets into the combined data and palette buffers, the mesh flags and
+    /// the offset of the occupancy pyramid into the data buffer
+    offsets: [u32; 4],
+    /// Occupied box in cells, minimum in the low and exclusive maximum in
+    /// the high 16 bits of each axis
+    bounds: [u32; 4],
+}
+
+/// Range of [`VoxelInstances`] drawn by one phase item
+#[derive(Component)]
+pub(crate) struct VoxelBatch {
+    pub(crate) instances: Range<u32>,
+}
+
+/// Instances of every batch queued this frame
+pub(crate) struct VoxelInstances {
+    pub(crate) buffer: BufferVec<VoxelInstance>,
+}
+
+impl VoxelInstance {
+    fn new(
+        mesh_uniform: &MeshUniform,
+        layout: [u32; 4],
+        offsets: [u32; 4],
+        (min, max): (UVec3, UVec3),
+    ) -> Self {
+        let bounds = |axis: usize| min[axis] | (max[axis] << 16);
+        Self {
+            model: mesh_uniform.transform.to_cols_array_2d(),
+            inverse_model: mesh_uniform.transform.inverse().to_cols_array_2d(),
+            layout,
+            offsets,
+            bounds: [bounds(0), bounds(1), bounds(2), 0],
+        }
+    }
+
+    pub(crate) fn buffer_layout() -> VertexBufferLayout {
+        let matrices = (0..8).map(|i| (VertexFormat::Float32x4, i));
+        let vectors = (8..11).map(|i| (VertexFormat::Uint32x4, i));
+        VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as u64,
+            step_mode: VertexStepMode::Instance,
+            attributes: matrices
+                .chain(vectors)
+                .map(|(format, i)| VertexAttribute {
+                    format,
+                    offset: i as u64 * 16,
+                    shader_location: FIRST_INSTANCE_LOCATION + i,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for VoxelInstances {
+    fn default() -> Self {
+        Self {
+            buffer: BufferVec::new(BufferUsages::VERTEX),
+        }
+    }
+}
+
+impl VoxelInstances {
+    /// Pushes instances of `voxels` sorted front to back, or back to front
+    /// for blended phases, and spawns a [`VoxelBatch`] for every
+    /// [`BATCH_SIZE`] of them.
+    ///
+    /// Returns the batch entities along with the distance of their nearest
+    /// instance. Other items of the phase are only sorted against that
+    /// distance, so a blended item lying between the instances of one batch
+    /// is drawn before all of them.
+    pub(crate) fn push_batches<'a>(
+        &mut self,
+        commands: &mut Commands,
+        storage: &VoxelStorage,
+        distance: impl Fn(&Mat4) -> f32,
+        back_to_front: bool,
+        voxels: impl Iterator<Item = (&'a Voxel, &'a MeshUniform)>,
+    ) -> Vec<(Entity, f32)> {
+        let mut sorted: Vec<_> = voxels
+            .filter_map(|(voxel, mesh_uniform)| {
+                let slot = storage.slot(&voxel.data)?;
+                // Nothing to draw in an empty grid
+                let bounds = slot.bounds?;
+                Some((
+                    distance(&mesh_uniform.transform),
+                    mesh_uniform,
+                    slot,
+                    bounds,
+                ))
+            })
+            .collect();
+        // Distances are view space depths, which grow towards the camera
+        if back_to_front {
+            sorted.sort_by(|(a, ..), (b, ..)| a.total_cmp(b));
+        } else {
+            sorted.sort_by(|(a, ..), (b, ..)| b.total_cmp(a));
+        }
+
+        sorted
+            .chunks(BATCH_SIZE)
+            .map(|chunk| {
+                let start = self.buffer.len() as u32;
+                for (_, mesh_uniform, slot, bounds) in chunk {
+                    let size = slot.size;
+                    self.buffer.push(VoxelInstance::new(
+                        mesh_uniform,
+                        [size.x, size.y, size.z, slot.format],
+                        [
+                            slot.data.start as u32,
+                            slot.palette.start as u32,
+                            mesh_uniform.flags,
+                            (slot.data.start + slot.cells) as u32,
+                        ],
+                        *bounds,
+                    ));
+                }
+                let instances = start..self.buffer.len() as u32;
+                let batch = commands.spawn().insert(VoxelBatch { instances }).id();
+                // Distances grow towards the camera
+                let nearest = chunk
+                    .iter()
+                    .map(|(distance, ..)| *distance)
+                    .fold(f32::MIN, f32::max);
+                (batch, nearest)
+            })
+            .collect()
+    }
+
+    /// Pushes the instance of a [`SparseVoxel`] entity and spawns the batch
+    /// drawing it with the bind group of its asset, `None` if it is empty
+    pub(crate) fn push_sparse(
+        &mut self,
+        commands: &mut Commands,
+        voxel: &SparseVoxel,
+        data: &GpuSparseVoxelData,
+        mesh_uniform: &MeshUniform,
+    ) -> Option<Entity> {
+        let bounds = data.bounds?;
+        let size = data.size;
+        let start = self.buffer.len() as u32;
+        // Colors straight from the tree, the depth takes the place of the
+        // occupancy pyramid offset
+        self.buffer.push(VoxelInstance::new(
+            mesh_uniform,
+            [size.x, size.y, size.z, 0],
+            [0, 0, mesh_uniform.flags, data.depth],
+            bounds,
+        ));
+        let instances = start..self.buffer.len() as u32;
+        Some(
+            commands
+                .spawn()
+                .insert_bundle((VoxelBatch { instances }, voxel.clone()))
+                .id(),
+        )
+    }
+}
+
+pub(crate) fn clear_voxel_instances(mut instances: ResMut<VoxelInstances>) {
+    instances.buffer.clear();
+}
+
+pub(crate) fn write_voxel_instances(
+    mut instances: ResMut<VoxelInstances>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    instances.buffer.write_buffer(&render_device, &render_queue);
+}
@@ -1,6 +1,7 @@
 use bevy::{
     asset::load_internal_asset,
-    core_pipeline::core_3d::AlphaMask3d,
+    core_pipeline::core_3d::{AlphaMask3d, Transparent3d},
+    pbr::Shadow,
     prelude::*,
     render::{
         extract_component::ExtractComponentPlugin, extract_resource::ExtractResourcePlugin,
@@ -9,20 +10,55 @@ use bevy::{
     },
 };
 
+mod bounds;
 mod bundle;
+mod collider;
+mod collision;
 mod draw;
+mod emissive;
+mod extract_voxel_data;
 mod extract_voxel_mesh_uniforms;
+mod generator;
+mod instance;
+mod meshing;
+mod noise;
+pub mod picking;
 mod pipeline;
 mod queue;
+mod raycast;
+mod sparse;
+mod storage;
+mod streaming;
+pub mod vox;
 mod voxel;
 mod voxel_mesh;
+mod voxel_type;
 pub mod wireframe;
+mod world;
 
-pub use bundle::VoxelBundle;
-pub use voxel::{Voxel, VoxelData};
+pub use bundle::{SparseVoxelBundle, VoxelBundle};
+pub use collider::{ColliderBox, ColliderTrimesh, VoxelCollider, VoxelColliderSettings};
+pub use collision::{VoxelCollision, VoxelContact, VoxelSlide};
+pub use emissive::{VoxelEmissiveLight, VoxelEmissiveLights};
+pub use generator::{
+    CaveCarver, FlatGenerator, HeightmapError, HeightmapGenerator, NoiseDensityGenerator,
+    NoiseHeightGenerator, TerrainLayers, VoxelGenerator, VoxelWorldGenerator,
+};
+pub use meshing::{greedy_mesh, VoxelRenderMode};
+pub use noise::{Noise, NoiseKind};
+pub use pipeline::VoxelSettings;
+pub use raycast::{VoxelRayHit, VoxelRaycast};
+pub use sparse::{SparseVoxel, SparseVoxelData};
+pub use streaming::{ChunkLoaded, ChunkLoader, ChunkStreamingBudget, ChunkUnloaded};
+pub use voxel::{Voxel, VoxelCells, VoxelData, VoxelDataError};
+pub use voxel_type::{VoxelType, VoxelTypeId, VoxelTypeRegistry};
+pub use world::{VoxelWorld, CHUNK_SIZE};
 
+use extract_voxel_data::{extract_voxel_data, prepare_voxel_data, ExtractedVoxelData};
 use extract_voxel_mesh_uniforms::extract_voxel_meshes;
+use instance::{clear_voxel_instances, write_voxel_instances, VoxelInstances};
 use pipeline::VOXEL_SHADER_HANDLE;
+use storage::VoxelStorage;
 
 pub struct VoxelPlugin;
 
@@ -37,15 +73,60 @@ impl Plugin for VoxelPlugin {
 
         app.add_plugin(ExtractComponentPlugin::<voxel::Voxel>::default())
             .add_asset::<VoxelData>()
-            .add_plugin(RenderAssetPlugin::<VoxelData>::default())
+            .add_asset::<vox::VoxScene>()
+            .init_asset_loader::<vox::VoxLoader>()
+            .add_system(vox::spawn_vox_scenes)
+            .add_system(emissive::update_emissive_lights)
+            .add_system(bounds::update_voxel_aabbs)
+            .add_plugin(ExtractComponentPlugin::<SparseVoxel>::default())
+            .add_asset::<SparseVoxelData>()
+            .add_plugin(RenderAssetPlugin::<SparseVoxelData>::default())
+            .add_system(bounds::update_sparse_voxel_aabbs)
+            .add_system(meshing::update_meshed_voxels)
+            .add_system(collider::update_voxel_colliders)
+            .add_system(world::update_world_chunks)
+            .init_resource::<streaming::ChunkStreamingBudget>()
+            .add_event::<ChunkLoaded>()
+            .add_event::<ChunkUnloaded>()
+            .add_system(streaming::stream_chunks)
+            .add_system(generator::start_chunk_generation)
+            .add_system(generator::finish_chunk_generation)
             .add_plugin(ExtractResourcePlugin::<voxel_mesh::VoxelMesh>::default())
-            .init_resource::<voxel_mesh::VoxelMesh>();
+            .init_resource::<voxel_mesh::VoxelMesh>()
+            .add_plugin(ExtractResourcePlugin::<VoxelTypeRegistry>::default())
+            .init_resource::<VoxelTypeRegistry>();
 
+        let settings = app
+            .world
+            .get_resource_or_insert_with(VoxelSettings::default)
+            .clone();
         app.sub_app_mut(RenderApp)
+            .insert_resource(settings)
             .add_render_command::<AlphaMask3d, draw::DrawVoxels>()
+            .add_render_command::<Transparent3d, draw::DrawVoxels>()
+            .add_render_command::<Shadow, draw::DrawVoxelShadows>()
+            .add_render_command::<AlphaMask3d, draw::DrawSparseVoxels>()
+            .add_render_command::<Transparent3d, draw::DrawSparseVoxels>()
+            .add_render_command::<Shadow, draw::DrawSparseVoxelShadows>()
             .init_resource::<pipeline::VoxelPipeline>()
             .init_resource::<SpecializedMeshPipelines<pipeline::VoxelPipeline>>()
+            .init_resource::<pipeline::VoxelShadowPipeline>()
+            .init_resource::<SpecializedMeshPipelines<pipeline::VoxelShadowPipeline>>()
+            .init_resource::<pipeline::SparseVoxelPipeline>()
+            .init_resource::<SpecializedMeshPipelines<pipeline::SparseVoxelPipeline>>()
+            .init_resource::<pipeline::SparseVoxelShadowPipeline>()
+            .init_resource::<SpecializedMeshPipelines<pipeline::SparseVoxelShadowPipeline>>()
+            .init_resource::<VoxelStorage>()
+            .init_resource::<VoxelInstances>()
+            .init_resource::<ExtractedVoxelData>()
+            .add_system_to_stage(RenderStage::Extract, extract_voxel_data)
             .add_system_to_stage(RenderStage::Extract, extract_voxel_meshes)
-            .add_system_to_stage(RenderStage::Queue, queue::queue_voxel);
+            .add_system_to_stage(RenderStage::Prepare, prepare_voxel_data)
+            .add_system_to_stage(RenderStage::Prepare, clear_voxel_instances)
+            .add_system_to_stage(RenderStage::Queue, queue::queue_voxel)
+            .add_system_to_stage(RenderStage::Queue, queue::queue_voxel_shadows)
+            .add_system_to_stage(RenderStage::Queue, queue::queue_sparse_voxels)
+            .add_system_to_stage(RenderStage::Queue, queue::queue_sparse_voxel_shadows)
+            .add_system_to_stage(RenderStage::PhaseSort, write_voxel_instances);
     }
 }
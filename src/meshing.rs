@@ -0,0 +1,373 @@
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+    utils::{HashMap, HashSet},
+};
+
+use super::{
+    voxel::{Voxel, VoxelCells, VoxelData},
+    voxel_type::{VoxelTypeId, VoxelTypeRegistry},
+};
+
+/// How a [`Voxel`] entity is drawn
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoxelRenderMode {
+    /// Raymarched on the GPU through a proxy box, the default
+    Raymarched,
+    /// Converted with [`greedy_mesh`] and drawn as a regular PBR mesh.
+    ///
+    /// Only base colors are carried over as vertex colors, emissive and
+    /// other properties of voxel types are ignored and the mesh is opaque.
+    Meshed,
+}
+
+impl Default for VoxelRenderMode {
+    fn default() -> Self {
+        VoxelRenderMode::Raymarched
+    }
+}
+
+/// Rectangle of merged cell faces, in grid space
+pub(crate) struct GreedyQuad {
+    pub origin: Vec3,
+    pub along_u: Vec3,
+    pub along_v: Vec3,
+    /// Outward axis aligned normal
+    pub normal: Vec3,
+    /// Key shared by every merged face
+    pub key: u32,
+}
+
+impl GreedyQuad {
+    /// Corners counter-clockwise when seen from the front, with UVs counting
+    /// cells so textures can tile
+    pub fn vertices(&self) -> [(Vec3, Vec2); 4] {
+        let (width, height) = (self.along_u.length(), self.along_v.length());
+        let u = (self.origin + self.along_u, Vec2::new(width, 0.0));
+        let v = (self.origin + self.along_v, Vec2::new(0.0, height));
+        let uv = (
+            self.origin + self.along_u + self.along_v,
+            Vec2::new(width, height),
+        );
+        // The quad axes are right handed with the normal of positive faces
+        if self.normal.max_element() > 0.0 {
+            [(self.origin, Vec2::ZERO), u, uv, v]
+        } else {
+            [(self.origin, Vec2::ZERO), v, uv, u]
+        }
+    }
+}
+
+/// Merges the visible faces of the cells of a `size` grid into quads.
+///
+/// `key` gives `None` for empty cells, faces merge when their keys match.
+/// Faces are hidden by occupied neighbors, unless `translucent` is true for
+/// the neighbor key and it differs from the face's.
+pub(crate) fn greedy_quads(
+    size: UVec3,
+    key: impl Fn(usize) -> Option<u32>,
+    translucent: impl Fn(u32) -> bool,
+    mut quad: impl FnMut(GreedyQuad),
+) {
+    let dims = size.to_array().map(|axis| axis as usize);
+    let cell = |position: [usize; 3]| {
+        key(position[0] + position[2] * dims[0] + position[1] * dims[0] * dims[2])
+    };
+
+    for d in 0..3 {
+        let (u, v) = ((d + 1) % 3, (d + 2) % 3);
+        let mut mask = vec![None; dims[u] * dims[v]];
+
+        for sign in [-1i32, 1] {
+            for slice in 0..dims[d] {
+                // Faces of this slice not hidden by the neighbor they look at
+                for j in 0..dims[v] {
+                    for i in 0..dims[u] {
+                        let mut position = [0; 3];
+                        position[d] = slice;
+                        position[u] = i;
+                        position[v] = j;
+                        let face = cell(position).filter(|&face| {
+                            let neighbor = slice as i32 + sign;
+                            if neighbor < 0 || neighbor >= dims[d] as i32 {
+                                return true;
+                            }
+                            position[d] = neighbor as usize;
+                            match cell(position) {
+                                None => true,
+                                Some(other) => other != face && translucent(other),
+                            }
+                        });
+                        mask[i + j * dims[u]] = face;
+                    }
+                }
+
+                for j in 0..dims[v] {
+                    let mut i = 0;
+                    while i < dims[u] {
+                        let face = match mask[i + j * dims[u]] {
+                            Some(face) => face,
+                            None => {
+                                i += 1;
+                                continue;
+                            }
+                        };
+
+                        let mut width = 1;
+                        while i + width < dims[u] && mask[i + width + j * dims[u]] == Some(face) {
+                            width += 1;
+                        }
+                        let mut height = 1;
+                        while j + height < dims[v]
+                            && (i..i + width)
+                                .all(|k| mask[k + (j + height) * dims[u]] == Some(face))
+                        {
+                            height += 1;
+                        }
+                        for row in j..j + height {
+                            mask[i + row * dims[u]..i + width + row * dims[u]].fill(None);
+                        }
+
+                        let mut origin = Vec3::ZERO;
+                        origin[d] = (slice + usize::from(sign > 0)) as f32;
+                        origin[u] = i as f32;
+                        origin[v] = j as f32;
+                        let mut along_u = Vec3::ZERO;
+                        along_u[u] = width as f32;
+                        let mut along_v = Vec3::ZERO;
+                        along_v[v] = height as f32;
+                        let mut normal = Vec3::ZERO;
+                        normal[d] = sign as f32;
+                        quad(GreedyQuad {
+                            origin,
+                            along_u,
+                            along_v,
+                            normal,
+                            key: face,
+                        });
+
+                        i += width;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds a triangle mesh of the visible faces of `data`, merging adjacent
+/// faces of the same color into larger quads.
+///
+/// The mesh occupies the same space as the raymarched proxy: the largest grid
+/// dimension spans one unit around the origin. Colors are stored linear in
+/// [`Mesh::ATTRIBUTE_COLOR`], UVs count cells along each quad so textures can tile.
+pub fn greedy_mesh(data: &VoxelData, types: &VoxelTypeRegistry) -> Mesh {
+    let size = data.size();
+    let max_size = size.max_element().max(1) as f32;
+    let half = size.as_vec3() * 0.5;
+
+    // Faces merge when their keys match: the color, or the type for typed grids
+    let cells = data.cells();
+    let key = |index: usize| -> Option<u32> {
+        if !cells.is_occupied(index) {
+            return None;
+        }
+        Some(match cells {
+            VoxelCells::Typed(ids) => ids[index] as u32,
+            _ => cells.color(index),
+        })
+    };
+    let translucent = |key: u32| match cells {
+        VoxelCells::Typed(_) => types
+            .get(VoxelTypeId(key as u16))
+            .map_or(false, |voxel_type| voxel_type.is_translucent()),
+        _ => key >> 24 != 0xFF,
+    };
+    let mut colors = HashMap::<u32, [f32; 4]>::default();
+    let mut color = |key: u32| {
+        *colors.entry(key).or_insert_with(|| match cells {
+            VoxelCells::Typed(_) => types
+                .get(VoxelTypeId(key as u16))
+                .map_or([1.0; 4], |voxel_type| {
+                    voxel_type.base_color.as_linear_rgba_f32()
+                }),
+            _ => {
+                // Read as linear like `voxel.wgsl` does
+                let [a, r, g, b] = key.to_be_bytes();
+                [r, g, b, a].map(|channel| channel as f32 / 255.0)
+            }
+        })
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut vertex_colors = Vec::new();
+    let mut indices = Vec::new();
+    greedy_quads(size, key, translucent, |quad| {
+        let start = positions.len() as u32;
+        for (position, uv) in quad.vertices() {
+            positions.push(((position - half) / max_size).to_array());
+            normals.push(quad.normal.to_array());
+            uvs.push(uv.to_array());
+            vertex_colors.push(color(quad.key));
+        }
+        indices.extend([0, 1, 2, 0, 2, 3].map(|i| start + i));
+    });
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, vertex_colors);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+/// Meshes shared by every [`VoxelRenderMode::Meshed`] entity using an asset
+#[derive(Default)]
+pub(crate) struct VoxelMeshes {
+    meshes: HashMap<Handle<VoxelData>, Handle<Mesh>>,
+    material: Option<Handle<StandardMaterial>>,
+    /// Entities given a mesh and material here, others keep theirs
+    entities: HashSet<Entity>,
+}
+
+/// Gives meshed voxel entities a mesh and material, which also keeps them out
+/// of the raymarching pipeline, and takes them away in raymarched mode
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::type_complexity)]
+pub(crate) fn update_meshed_voxels(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<VoxelData>>,
+    assets: Res<Assets<VoxelData>>,
+    types: Res<VoxelTypeRegistry>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut voxel_meshes: Local<VoxelMeshes>,
+    voxels: Query<(Entity, &Voxel, ChangeTrackers<Voxel>, &VoxelRenderMode)>,
+    removed: RemovedComponents<Voxel>,
+) {
+    let voxel_meshes = &mut *voxel_meshes;
+    for entity in removed.iter() {
+        voxel_meshes.entities.remove(&entity);
+    }
+
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                // Meshes in use are rebuilt in place, so entities keep their handles
+                if let (Some(mesh), Some(data)) =
+                    (voxel_meshes.meshes.get(handle), assets.get(handle))
+                {
+                    meshes.set_untracked(mesh, greedy_mesh(data, &types));
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                voxel_meshes.meshes.remove(handle);
+            }
+        }
+    }
+
+    if types.is_changed() {
+        for (handle, mesh) in &voxel_meshes.meshes {
+            if let Some(data) = assets.get(handle) {
+                meshes.set_untracked(mesh, greedy_mesh(data, &types));
+            }
+        }
+    }
+
+    // Entities leaving meshed mode or switching assets can leave meshes unused
+    let mut released = false;
+    for (entity, voxel, tracker, mode) in voxels.iter() {
+        let meshed = voxel_meshes.entities.contains(&entity);
+        match mode {
+            VoxelRenderMode::Meshed => {
+                // Entities whose asset was not loaded yet are retried every frame
+                if meshed && !tracker.is_changed() {
+                    continue;
+                }
+                released |= meshed;
+                let data = match assets.get(&voxel.data) {
+                    Some(data) => data,
+                    None => continue,
+                };
+                let mesh = voxel_meshes
+                    .meshes
+                    .entry(voxel.data.clone_weak())
+                    .or_insert_with(|| meshes.add(greedy_mesh(data, &types)))
+                    .clone();
+                // Vertex colors are multiplied with a white base color
+                let material = voxel_meshes
+                    .material
+                    .get_or_insert_with(|| materials.add(StandardMaterial::default()))
+                    .clone();
+                commands.entity(entity).insert_bundle((mesh, material));
+                voxel_meshes.entities.insert(entity);
+            }
+            VoxelRenderMode::Raymarched => {
+                if voxel_meshes.entities.remove(&entity) {
+                    commands
+                        .entity(entity)
+                        .remove::<Handle<Mesh>>()
+                        .remove::<Handle<StandardMaterial>>();
+                    released = true;
+                }
+            }
+        }
+    }
+
+    if released {
+        let used: HashSet<_> = voxels
+            .iter()
+            .filter(|(_, _, _, mode)| **mode == VoxelRenderMode::Meshed)
+            .map(|(_, voxel, ..)| voxel.data.id)
+            .collect();
+        // Dropping the last strong handle frees the mesh
+        voxel_meshes
+            .meshes
+            .retain(|handle, _| used.contains(&handle.id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::greedy_mesh;
+    use crate::{VoxelData, VoxelTypeRegistry};
+
+    /// Quads in the mesh of `cells`, given as position and ARGB color
+    fn quads(size: UVec3, cells: &[(UVec3, u32)]) -> usize {
+        let mut data = VoxelData::new(size);
+        for &(position, color) in cells {
+            data.set(position, color).unwrap();
+        }
+        greedy_mesh(&data, &VoxelTypeRegistry::default()).count_vertices() / 4
+    }
+
+    #[test]
+    fn meshes_a_single_cell() {
+        assert_eq!(quads(UVec3::ONE, &[(UVec3::ZERO, 0xFFFF_0000)]), 6);
+    }
+
+    #[test]
+    fn merges_faces_of_the_same_color() {
+        let size = UVec3::new(2, 1, 1);
+        let same = [(UVec3::ZERO, 0xFFFF_0000), (UVec3::X, 0xFFFF_0000)];
+        assert_eq!(quads(size, &same), 6);
+        // The shared face stays hidden, the four sides no longer merge
+        let different = [(UVec3::ZERO, 0xFFFF_0000), (UVec3::X, 0xFF00_FF00)];
+        assert_eq!(quads(size, &different), 10);
+    }
+
+    #[test]
+    fn keeps_faces_behind_translucent_neighbors() {
+        let size = UVec3::new(2, 1, 1);
+        // The opaque cell shows through, the translucent one doesn't show its back
+        let cells = [(UVec3::ZERO, 0xFFFF_0000), (UVec3::X, 0x8000_FF00)];
+        assert_eq!(quads(size, &cells), 11);
+        // Matching translucent cells merge like opaque ones
+        let cells = [(UVec3::ZERO, 0x8000_FF00), (UVec3::X, 0x8000_FF00)];
+        assert_eq!(quads(size, &cells), 6);
+    }
+}
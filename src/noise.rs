@@ -0,0 +1,297 @@
+use bevy::prelude::*;
+
+/// Base function summed over the octaves of a [`Noise`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoiseKind {
+    /// Random values at lattice points, smoothly interpolated
+    Value,
+    /// Simplex gradient noise, fewer grid artifacts than value noise
+    Simplex,
+}
+
+/// Seeded fractal noise returning values in `-1.0..=1.0`
+#[derive(Clone, Debug)]
+pub struct Noise {
+    pub kind: NoiseKind,
+    pub seed: u32,
+    /// Features per unit at the first octave
+    pub frequency: f32,
+    pub octaves: u32,
+    /// Amplitude multiplier from one octave to the next
+    pub persistence: f32,
+    /// Frequency multiplier from one octave to the next
+    pub lacunarity: f32,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            kind: NoiseKind::Simplex,
+            seed: 0,
+            frequency: 1.0 / 64.0,
+            octaves: 4,
+            persistence: 0.5,
+            lacunarity: 2.0,
+        }
+    }
+}
+
+impl Noise {
+    /// Samples the 2D noise at `position`
+    pub fn sample2(&self, position: Vec2) -> f32 {
+        self.fractal(|seed, frequency| {
+            let position = position * frequency;
+            match self.kind {
+                NoiseKind::Value => value2(seed, position),
+                NoiseKind::Simplex => simplex2(seed, position),
+            }
+        })
+    }
+
+    /// Samples the 3D noise at `position`
+    pub fn sample3(&self, position: Vec3) -> f32 {
+        self.fractal(|seed, frequency| {
+            let position = position * frequency;
+            match self.kind {
+                NoiseKind::Value => value3(seed, position),
+                NoiseKind::Simplex => simplex3(seed, position),
+            }
+        })
+    }
+
+    fn fractal(&self, octave: impl Fn(u32, f32) -> f32) -> f32 {
+        let mut sum = 0.0;
+        let mut total_amplitude = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = self.frequency;
+        for i in 0..self.octaves.max(1) {
+            sum += octave(self.seed.wrapping_add(i), frequency) * amplitude;
+            total_amplitude += amplitude;
+            amplitude *= self.persistence;
+            frequency *= self.lacunarity;
+        }
+        sum / total_amplitude
+    }
+}
+
+fn hash(seed: u32, x: i32, y: i32, z: i32) -> u32 {
+    let mut h = seed
+        ^ (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^ (h >> 16)
+}
+
+/// Hash mapped to `-1.0..=1.0`
+fn lattice(seed: u32, x: i32, y: i32, z: i32) -> f32 {
+    hash(seed, x, y, z) as f32 / u32::MAX as f32 * 2.0 - 1.0
+}
+
+fn smooth(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn value2(seed: u32, position: Vec2) -> f32 {
+    let cell = position.floor();
+    let (x, y) = (cell.x as i32, cell.y as i32);
+    let t = (position - cell).to_array().map(smooth);
+    let bottom = lerp(lattice(seed, x, y, 0), lattice(seed, x + 1, y, 0), t[0]);
+    let top = lerp(
+        lattice(seed, x, y + 1, 0),
+        lattice(seed, x + 1, y + 1, 0),
+        t[0],
+    );
+    lerp(bottom, top, t[1])
+}
+
+fn value3(seed: u32, position: Vec3) -> f32 {
+    let cell = position.floor();
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+    let t = (position - cell).to_array().map(smooth);
+    let plane = |z| {
+        let bottom = lerp(lattice(seed, x, y, z), lattice(seed, x + 1, y, z), t[0]);
+        let top = lerp(
+            lattice(seed, x, y + 1, z),
+            lattice(seed, x + 1, y + 1, z),
+            t[0],
+        );
+        lerp(bottom, top, t[1])
+    };
+    lerp(plane(z), plane(z + 1), t[2])
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+const GRADIENTS_2D: [Vec2; 8] = [
+    Vec2::new(1.0, 1.0),
+    Vec2::new(-1.0, 1.0),
+    Vec2::new(1.0, -1.0),
+    Vec2::new(-1.0, -1.0),
+    Vec2::new(1.0, 0.0),
+    Vec2::new(-1.0, 0.0),
+    Vec2::new(0.0, 1.0),
+    Vec2::new(0.0, -1.0),
+];
+
+const GRADIENTS_3D: [Vec3; 12] = [
+    Vec3::new(1.0, 1.0, 0.0),
+    Vec3::new(-1.0, 1.0, 0.0),
+    Vec3::new(1.0, -1.0, 0.0),
+    Vec3::new(-1.0, -1.0, 0.0),
+    Vec3::new(1.0, 0.0, 1.0),
+    Vec3::new(-1.0, 0.0, 1.0),
+    Vec3::new(1.0, 0.0, -1.0),
+    Vec3::new(-1.0, 0.0, -1.0),
+    Vec3::new(0.0, 1.0, 1.0),
+    Vec3::new(0.0, -1.0, 1.0),
+    Vec3::new(0.0, 1.0, -1.0),
+    Vec3::new(0.0, -1.0, -1.0),
+];
+
+fn simplex2(seed: u32, position: Vec2) -> f32 {
+    // Skew to the grid of squares made of two simplices and back
+    const F2: f32 = 0.366_025_42;
+    const G2: f32 = 0.211_324_87;
+
+    let skew = (position.x + position.y) * F2;
+    let cell = (position + skew).floor();
+    let unskew = (cell.x + cell.y) * G2;
+    let d0 = position - (cell - unskew);
+    let step = if d0.x > d0.y { Vec2::X } else { Vec2::Y };
+    let corners = [
+        (Vec2::ZERO, d0),
+        (step, d0 - step + G2),
+        (Vec2::ONE, d0 - 1.0 + 2.0 * G2),
+    ];
+
+    let (x, y) = (cell.x as i32, cell.y as i32);
+    let sum: f32 = corners
+        .iter()
+        .map(|(offset, d)| {
+            let t = 0.5 - d.length_squared();
+            if t <= 0.0 {
+                return 0.0;
+            }
+            let h = hash(seed, x + offset.x as i32, y + offset.y as i32, 0);
+            t.powi(4) * GRADIENTS_2D[h as usize % 8].dot(*d)
+        })
+        .sum();
+    (70.0 * sum).clamp(-1.0, 1.0)
+}
+
+fn simplex3(seed: u32, position: Vec3) -> f32 {
+    const F3: f32 = 1.0 / 3.0;
+    const G3: f32 = 1.0 / 6.0;
+
+    let skew = (position.x + position.y + position.z) * F3;
+    let cell = (position + skew).floor();
+    let unskew = (cell.x + cell.y + cell.z) * G3;
+    let d0 = position - (cell - unskew);
+
+    // The simplex holding the point is found by ordering its coordinates
+    let (step1, step2) = if d0.x >= d0.y {
+        if d0.y >= d0.z {
+            (Vec3::X, Vec3::X + Vec3::Y)
+        } else if d0.x >= d0.z {
+            (Vec3::X, Vec3::X + Vec3::Z)
+        } else {
+            (Vec3::Z, Vec3::X + Vec3::Z)
+        }
+    } else if d0.y < d0.z {
+        (Vec3::Z, Vec3::Y + Vec3::Z)
+    } else if d0.x < d0.z {
+        (Vec3::Y, Vec3::Y + Vec3::Z)
+    } else {
+        (Vec3::Y, Vec3::X + Vec3::Y)
+    };
+    let corners = [
+        (Vec3::ZERO, d0),
+        (step1, d0 - step1 + G3),
+        (step2, d0 - step2 + 2.0 * G3),
+        (Vec3::ONE, d0 - 1.0 + 3.0 * G3),
+    ];
+
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+    let sum: f32 = corners
+        .iter()
+        .map(|(offset, d)| {
+            let t = 0.6 - d.length_squared();
+            if t <= 0.0 {
+                return 0.0;
+            }
+            let h = hash(
+                seed,
+                x + offset.x as i32,
+                y + offset.y as i32,
+                z + offset.z as i32,
+            );
+            t.powi(4) * GRADIENTS_3D[h as usize % 12].dot(*d)
+        })
+        .sum();
+    (32.0 * sum).clamp(-1.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{Noise, NoiseKind};
+
+    fn noises() -> impl Iterator<Item = Noise> {
+        [NoiseKind::Value, NoiseKind::Simplex]
+            .map(|kind| Noise {
+                kind,
+                seed: 7,
+                frequency: 0.37,
+                ..default()
+            })
+            .into_iter()
+    }
+
+    fn positions() -> impl Iterator<Item = Vec3> {
+        (0..1000).map(|i| Vec3::new(i as f32 * 0.731, i as f32 * -1.37, (i % 17) as f32 * 2.9))
+    }
+
+    #[test]
+    fn stays_in_range() {
+        for noise in noises() {
+            for position in positions() {
+                let value2 = noise.sample2(position.truncate());
+                let value3 = noise.sample3(position);
+                assert!(
+                    (-1.0..=1.0).contains(&value2),
+                    "{:?} gave {}",
+                    noise.kind,
+                    value2
+                );
+                assert!(
+                    (-1.0..=1.0).contains(&value3),
+                    "{:?} gave {}",
+                    noise.kind,
+                    value3
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn depends_on_the_seed_only() {
+        for noise in noises() {
+            let same = noise.clone();
+            let other = Noise {
+                seed: noise.seed + 1,
+                ..noise.clone()
+            };
+            assert!(positions().all(|p| noise.sample3(p) == same.sample3(p)));
+            assert!(positions().all(|p| noise.sample2(p.truncate()) == same.sample2(p.truncate())));
+            assert!(positions().any(|p| noise.sample3(p) != other.sample3(p)));
+            assert!(positions().any(|p| noise.sample2(p.truncate()) != other.sample2(p.truncate())));
+        }
+    }
+}
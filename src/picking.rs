@@ -0,0 +1,116 @@
+use bevy::{prelude::*, render::camera::RenderTarget};
+
+use super::raycast::{VoxelRayHit, VoxelRaycast};
+
+/// Sends [`VoxelHovered`] and [`VoxelClicked`] for the voxel under the cursor
+/// of the primary window, seen through the first active [`Camera3d`]
+/// rendering to it
+#[derive(Debug, Default)]
+pub struct VoxelPickingPlugin;
+
+impl Plugin for VoxelPickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<VoxelHovered>()
+            .add_event::<VoxelClicked>()
+            .add_system(pick_voxels);
+    }
+}
+
+/// Sent every frame the cursor is over a visible voxel
+#[derive(Clone, Debug)]
+pub struct VoxelHovered {
+    pub entity: Entity,
+    /// Cell under the cursor
+    pub position: UVec3,
+    /// Face under the cursor, in grid space
+    pub normal: IVec3,
+    /// Cell in front of that face, to place a new voxel in
+    pub adjacent: IVec3,
+}
+
+/// Sent when a mouse button is pressed over a visible voxel
+#[derive(Clone, Debug)]
+pub struct VoxelClicked {
+    pub entity: Entity,
+    pub position: UVec3,
+    pub normal: IVec3,
+    pub adjacent: IVec3,
+    pub button: MouseButton,
+}
+
+impl From<&VoxelRayHit> for VoxelHovered {
+    fn from(hit: &VoxelRayHit) -> Self {
+        Self {
+            entity: hit.entity,
+            position: hit.position,
+            normal: hit.normal,
+            adjacent: hit.adjacent(),
+        }
+    }
+}
+
+/// World space ray through `cursor`, in logical pixels from the bottom left
+/// of the window
+fn cursor_ray(
+    camera: &Camera,
+    transform: &GlobalTransform,
+    window: &Window,
+    cursor: Vec2,
+) -> (Vec3, Vec3) {
+    let window_size = Vec2::new(window.width(), window.height());
+    let ndc = cursor / window_size * 2.0 - 1.0;
+    let ndc_to_world = transform.compute_matrix() * camera.projection_matrix().inverse();
+    // Depth is reversed, one is the near plane. The far plane of perspective
+    // projections is at infinity, so the ray goes through a point halfway.
+    let near = ndc_to_world.project_point3(ndc.extend(1.0));
+    let middle = ndc_to_world.project_point3(ndc.extend(0.5));
+    (near, (middle - near).normalize())
+}
+
+fn pick_voxels(
+    windows: Res<Windows>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    raycast: VoxelRaycast,
+    visibility: Query<&ComputedVisibility>,
+    mut hovered_events: EventWriter<VoxelHovered>,
+    mut clicked_events: EventWriter<VoxelClicked>,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let cursor = match window.cursor_position() {
+        Some(cursor) => cursor,
+        None => return,
+    };
+    let camera = cameras.iter().find(|(camera, _)| {
+        camera.is_active && matches!(camera.target, RenderTarget::Window(id) if id == window.id())
+    });
+    let (camera, transform) = match camera {
+        Some(camera) => camera,
+        None => return,
+    };
+
+    let (origin, direction) = cursor_ray(camera, transform, window, cursor);
+    let hit = raycast.cast_filtered(origin, direction, f32::INFINITY, |entity| {
+        visibility
+            .get(entity)
+            .map_or(false, ComputedVisibility::is_visible)
+    });
+    let hovered = match hit {
+        Some(hit) => VoxelHovered::from(&hit),
+        None => return,
+    };
+
+    for button in mouse_buttons.get_just_pressed() {
+        clicked_events.send(VoxelClicked {
+            entity: hovered.entity,
+            position: hovered.position,
+            normal: hovered.normal,
+            adjacent: hovered.adjacent,
+            button: *button,
+        });
+    }
+    hovered_events.send(hovered);
+}
@@ -1,25 +1,57 @@
 use bevy::{
-    pbr::{MeshPipeline, MeshPipelineKey},
+    pbr::{MeshPipeline, MeshPipelineKey, ShadowPipeline, ShadowPipelineKey},
     prelude::*,
     reflect::TypeUuid,
     render::{
         mesh::MeshVertexBufferLayout,
         render_resource::{
             BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType,
-            BufferBindingType, RenderPipelineDescriptor, ShaderStages, SpecializedMeshPipeline,
-            SpecializedMeshPipelineError,
+            BufferBindingType, FragmentState, RenderPipelineDescriptor, ShaderStages,
+            SpecializedMeshPipeline, SpecializedMeshPipelineError,
         },
         renderer::RenderDevice,
     },
 };
 
+use super::instance::VoxelInstance;
+
 pub const VOXEL_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 7632171639263852275);
 
+/// Shader features of the voxel pipelines.
+///
+/// Read once when [`VoxelPlugin`](crate::VoxelPlugin) is added, insert it
+/// before the plugin to change the defaults.
+#[derive(Clone, Debug)]
+pub struct VoxelSettings {
+    /// Darkens voxel faces next to occupied neighbors, costs up to eight
+    /// extra cell reads per shaded fragment
+    pub ambient_occlusion: bool,
+    /// Steps rays over empty 2³ and 4³ bricks instead of every cell
+    pub empty_space_skipping: bool,
+    /// Colors voxels by the number of traversal steps their rays took,
+    /// from blue to red, instead of shading them
+    pub iteration_heatmap: bool,
+}
+
+impl Default for VoxelSettings {
+    fn default() -> Self {
+        Self {
+            ambient_occlusion: true,
+            empty_space_skipping: true,
+            iteration_heatmap: false,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct VoxelPipeline {
     pub(crate) shader: Handle<Shader>,
     pub(crate) mesh_pipeline: MeshPipeline,
     pub(crate) voxel_data_bind_group_layout: BindGroupLayout,
+    pub(crate) ambient_occlusion: bool,
+    pub(crate) empty_space_skipping: bool,
+    pub(crate) iteration_heatmap: bool,
 }
 
 impl FromWorld for VoxelPipeline {
@@ -30,23 +62,49 @@ impl FromWorld for VoxelPipeline {
         let voxel_data_bind_group_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: Some("voxel data bind group"),
-                entries: &[BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
             });
 
         let mesh_pipeline = world.get_resource::<MeshPipeline>().unwrap();
+        let settings = world.get_resource::<VoxelSettings>().unwrap();
         VoxelPipeline {
             shader: VOXEL_SHADER_HANDLE.typed(),
             mesh_pipeline: mesh_pipeline.clone(),
             voxel_data_bind_group_layout,
+            ambient_occlusion: settings.ambient_occlusion,
+            empty_space_skipping: settings.empty_space_skipping,
+            iteration_heatmap: settings.iteration_heatmap,
         }
     }
 }
@@ -61,12 +119,181 @@ impl SpecializedMeshPipeline for VoxelPipeline {
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
         let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
         descriptor.vertex.shader = self.shader.clone();
-        descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
+        let fragment = descriptor.fragment.as_mut().unwrap();
+        fragment.shader = self.shader.clone();
+        // Blended voxels accumulate every see-through cell along the ray
+        if key.contains(MeshPipelineKey::TRANSPARENT_MAIN_PASS) {
+            fragment.shader_defs.push(String::from("VOXEL_TRANSPARENT"));
+        }
+        if self.ambient_occlusion {
+            fragment
+                .shader_defs
+                .push(String::from("VOXEL_AMBIENT_OCCLUSION"));
+        }
+        if self.empty_space_skipping {
+            fragment.shader_defs.push(String::from("VOXEL_SKIP_EMPTY"));
+        }
+        if self.iteration_heatmap {
+            fragment
+                .shader_defs
+                .push(String::from("VOXEL_ITERATION_HEATMAP"));
+        }
+        descriptor
+            .vertex
+            .buffers
+            .push(VoxelInstance::buffer_layout());
         descriptor.layout = Some(vec![
             self.mesh_pipeline.view_layout.clone(),
-            self.mesh_pipeline.mesh_layout.clone(),
             self.voxel_data_bind_group_layout.clone(),
         ]);
         Ok(descriptor)
     }
 }
+
+/// Raymarches voxels into shadow maps, writing only the depth of the hit
+#[derive(Clone)]
+pub struct VoxelShadowPipeline {
+    pub(crate) shader: Handle<Shader>,
+    pub(crate) shadow_pipeline: ShadowPipeline,
+    pub(crate) voxel_data_bind_group_layout: BindGroupLayout,
+    pub(crate) empty_space_skipping: bool,
+}
+
+impl FromWorld for VoxelShadowPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let shadow_pipeline = world.resource::<ShadowPipeline>().clone();
+        let voxel_pipeline = world.resource::<VoxelPipeline>();
+        VoxelShadowPipeline {
+            shader: voxel_pipeline.shader.clone(),
+            shadow_pipeline,
+            voxel_data_bind_group_layout: voxel_pipeline.voxel_data_bind_group_layout.clone(),
+            empty_space_skipping: voxel_pipeline.empty_space_skipping,
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for VoxelShadowPipeline {
+    type Key = ShadowPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.shadow_pipeline.specialize(key, layout)?;
+        let mut shader_defs = vec![String::from("SHADOW_PASS")];
+        if self.empty_space_skipping {
+            shader_defs.push(String::from("VOXEL_SKIP_EMPTY"));
+        }
+
+        // The shadow pipeline only binds positions, but the shader shares its
+        // vertex input with the main pass
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+        ])?;
+        descriptor.vertex.shader = self.shader.clone();
+        descriptor.vertex.shader_defs = shader_defs.clone();
+        descriptor.vertex.buffers = vec![vertex_layout, VoxelInstance::buffer_layout()];
+        descriptor.fragment = Some(FragmentState {
+            shader: self.shader.clone(),
+            shader_defs,
+            entry_point: "fragment".into(),
+            targets: vec![],
+        });
+        descriptor.layout = Some(vec![
+            self.shadow_pipeline.view_layout.clone(),
+            self.voxel_data_bind_group_layout.clone(),
+        ]);
+        Ok(descriptor)
+    }
+}
+
+/// Traverses the octree of [`SparseVoxelData`](crate::SparseVoxelData),
+/// bound on its own in place of the combined voxel buffers
+pub struct SparseVoxelPipeline {
+    pub(crate) voxel_pipeline: VoxelPipeline,
+    pub(crate) sparse_bind_group_layout: BindGroupLayout,
+}
+
+impl FromWorld for SparseVoxelPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let sparse_bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("sparse voxel bind group"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+        SparseVoxelPipeline {
+            voxel_pipeline: world.resource::<VoxelPipeline>().clone(),
+            sparse_bind_group_layout,
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for SparseVoxelPipeline {
+    type Key = MeshPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.voxel_pipeline.specialize(key, layout)?;
+        let fragment = descriptor.fragment.as_mut().unwrap();
+        fragment.shader_defs.push(String::from("VOXEL_SPARSE"));
+        descriptor.layout = Some(vec![
+            self.voxel_pipeline.mesh_pipeline.view_layout.clone(),
+            self.sparse_bind_group_layout.clone(),
+        ]);
+        Ok(descriptor)
+    }
+}
+
+/// Traverses the octree of [`SparseVoxelData`](crate::SparseVoxelData) into
+/// shadow maps
+pub struct SparseVoxelShadowPipeline {
+    pub(crate) shadow_pipeline: VoxelShadowPipeline,
+    pub(crate) sparse_bind_group_layout: BindGroupLayout,
+}
+
+impl FromWorld for SparseVoxelShadowPipeline {
+    fn from_world(world: &mut World) -> Self {
+        SparseVoxelShadowPipeline {
+            shadow_pipeline: world.resource::<VoxelShadowPipeline>().clone(),
+            sparse_bind_group_layout: world
+                .resource::<SparseVoxelPipeline>()
+                .sparse_bind_group_layout
+                .clone(),
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for SparseVoxelShadowPipeline {
+    type Key = ShadowPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.shadow_pipeline.specialize(key, layout)?;
+        let fragment = descriptor.fragment.as_mut().unwrap();
+        fragment.shader_defs.push(String::from("VOXEL_SPARSE"));
+        descriptor.layout = Some(vec![
+            self.shadow_pipeline.shadow_pipeline.view_layout.clone(),
+            self.sparse_bind_group_layout.clone(),
+        ]);
+        Ok(descriptor)
+    }
+}
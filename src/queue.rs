@@ -1,6 +1,11 @@
 use bevy::{
-    core_pipeline::core_3d::AlphaMask3d,
-    pbr::{MeshPipelineKey, MeshUniform},
+    core_pipeline::core_3d::{AlphaMask3d, Transparent3d},
+    ecs::system::SystemParam,
+    pbr::{
+        CubemapVisibleEntities, ExtractedDirectionalLight, ExtractedPointLight, LightEntity,
+        MeshPipelineKey, MeshUniform, NotShadowCaster, Shadow, ShadowPipelineKey,
+        ViewLightEntities,
+    },
     prelude::*,
     render::{
         render_asset::RenderAssets,
@@ -10,54 +15,344 @@ use bevy::{
     },
 };
 
-use super::{draw, pipeline, voxel, voxel_mesh};
+use super::{
+    draw,
+    instance::VoxelInstances,
+    pipeline,
+    sparse::{SparseVoxel, SparseVoxelData},
+    storage::VoxelStorage,
+    voxel, voxel_mesh,
+};
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn queue_voxel(
+    mut commands: Commands,
     alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
+    transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     voxel_pipeline: Res<pipeline::VoxelPipeline>,
     msaa: Res<Msaa>,
     mut pipelines: ResMut<SpecializedMeshPipelines<pipeline::VoxelPipeline>>,
     mut pipeline_cache: ResMut<PipelineCache>,
     meshes: Res<RenderAssets<Mesh>>,
     voxel_mesh: Res<voxel_mesh::VoxelMesh>,
-    voxels: Query<(Entity, &MeshUniform), With<voxel::Voxel>>,
+    storage: Res<VoxelStorage>,
+    mut instances: ResMut<VoxelInstances>,
+    voxels: Query<(&voxel::Voxel, &MeshUniform)>,
     mut views: Query<(
         &ExtractedView,
         &VisibleEntities,
         &mut RenderPhase<AlphaMask3d>,
+        &mut RenderPhase<Transparent3d>,
     )>,
 ) {
-    let draw_custom = alpha_mask_3d_draw_functions
+    let draw_alpha_mask = alpha_mask_3d_draw_functions
+        .read()
+        .get_id::<draw::DrawVoxels>()
+        .unwrap();
+    let draw_transparent = transparent_3d_draw_functions
         .read()
         .get_id::<draw::DrawVoxels>()
         .unwrap();
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples);
 
-    for (view, visible_entities, mut alpha_mask_phase) in views.iter_mut() {
+    let mesh = match meshes.get(&voxel_mesh.mesh) {
+        Some(mesh) => mesh,
+        None => return,
+    };
+    let key = msaa_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+    let alpha_mask_pipeline = pipelines
+        .specialize(&mut pipeline_cache, &voxel_pipeline, key, &mesh.layout)
+        .unwrap();
+    let transparent_pipeline = pipelines
+        .specialize(
+            &mut pipeline_cache,
+            &voxel_pipeline,
+            key | MeshPipelineKey::TRANSPARENT_MAIN_PASS,
+            &mesh.layout,
+        )
+        .unwrap();
+
+    for (view, visible_entities, mut alpha_mask_phase, mut transparent_phase) in views.iter_mut() {
+        let rangefinder = view.rangefinder3d();
+
+        let (translucent, opaque): (Vec<_>, Vec<_>) = visible_entities
+            .entities
+            .iter()
+            .filter_map(|visible_entity| voxels.get(*visible_entity).ok())
+            .partition(|(voxel, _)| {
+                storage
+                    .slot(&voxel.data)
+                    .map_or(false, |slot| slot.translucent)
+            });
+
+        let batches = instances.push_batches(
+            &mut commands,
+            &storage,
+            |transform| rangefinder.distance(transform),
+            false,
+            opaque.into_iter(),
+        );
+        for (entity, distance) in batches {
+            alpha_mask_phase.add(AlphaMask3d {
+                entity,
+                pipeline: alpha_mask_pipeline,
+                draw_function: draw_alpha_mask,
+                distance,
+            });
+        }
+
+        let batches = instances.push_batches(
+            &mut commands,
+            &storage,
+            |transform| rangefinder.distance(transform),
+            true,
+            translucent.into_iter(),
+        );
+        for (entity, distance) in batches {
+            transparent_phase.add(Transparent3d {
+                entity,
+                pipeline: transparent_pipeline,
+                draw_function: draw_transparent,
+                distance,
+            });
+        }
+    }
+}
+
+/// Shadow phases of every light view, along with the entities the light sees
+#[derive(SystemParam)]
+pub(crate) struct ShadowViews<'w, 's> {
+    view_lights: Query<'w, 's, &'static ViewLightEntities>,
+    view_light_shadow_phases: Query<
+        'w,
+        's,
+        (
+            &'static ExtractedView,
+            &'static LightEntity,
+            &'static mut RenderPhase<Shadow>,
+        ),
+    >,
+    point_light_entities: Query<'w, 's, &'static CubemapVisibleEntities, With<ExtractedPointLight>>,
+    directional_light_entities:
+        Query<'w, 's, &'static VisibleEntities, With<ExtractedDirectionalLight>>,
+}
+
+impl<'w, 's> ShadowViews<'w, 's> {
+    fn for_each(
+        &mut self,
+        mut queue: impl FnMut(&ExtractedView, &VisibleEntities, &mut RenderPhase<Shadow>),
+    ) {
+        for view_lights in self.view_lights.iter() {
+            for view_light_entity in view_lights.lights.iter().copied() {
+                let (view, light_entity, mut shadow_phase) =
+                    match self.view_light_shadow_phases.get_mut(view_light_entity) {
+                        Ok(view_light) => view_light,
+                        Err(_) => continue,
+                    };
+                let visible_entities = match light_entity {
+                    LightEntity::Directional { light_entity } => {
+                        self.directional_light_entities.get(*light_entity).ok()
+                    }
+                    LightEntity::Point {
+                        light_entity,
+                        face_index,
+                    } => self
+                        .point_light_entities
+                        .get(*light_entity)
+                        .ok()
+                        .map(|light| light.get(*face_index)),
+                };
+                // Lights with shadows disabled have no visible entities
+                if let Some(visible_entities) = visible_entities {
+                    queue(view, visible_entities, &mut *shadow_phase);
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_voxel_shadows(
+    mut commands: Commands,
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    shadow_pipeline: Res<pipeline::VoxelShadowPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<pipeline::VoxelShadowPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    voxel_mesh: Res<voxel_mesh::VoxelMesh>,
+    storage: Res<VoxelStorage>,
+    mut instances: ResMut<VoxelInstances>,
+    casting_voxels: Query<(&voxel::Voxel, &MeshUniform), Without<NotShadowCaster>>,
+    mut shadow_views: ShadowViews,
+) {
+    let draw_shadow = shadow_draw_functions
+        .read()
+        .get_id::<draw::DrawVoxelShadows>()
+        .unwrap();
+
+    let mesh = match meshes.get(&voxel_mesh.mesh) {
+        Some(mesh) => mesh,
+        None => return,
+    };
+    let key = ShadowPipelineKey::from_primitive_topology(mesh.primitive_topology);
+    let pipeline = pipelines
+        .specialize(&mut pipeline_cache, &shadow_pipeline, key, &mesh.layout)
+        .unwrap();
+
+    shadow_views.for_each(|view, visible_entities, shadow_phase| {
         let rangefinder = view.rangefinder3d();
+        let visible_voxels = visible_entities
+            .iter()
+            .filter_map(|visible_entity| casting_voxels.get(*visible_entity).ok());
 
-        if let Some(mesh) = meshes.get(&voxel_mesh.mesh) {
-            let add_render_phase = |(entity, mesh_uniform): (Entity, &MeshUniform)| {
-                let key =
-                    msaa_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
-                let pipeline = pipelines
-                    .specialize(&mut pipeline_cache, &voxel_pipeline, key, &mesh.layout)
-                    .unwrap();
+        let batches = instances.push_batches(
+            &mut commands,
+            &storage,
+            |transform| rangefinder.distance(transform),
+            false,
+            visible_voxels,
+        );
+        for (entity, distance) in batches {
+            shadow_phase.add(Shadow {
+                entity,
+                pipeline,
+                draw_function: draw_shadow,
+                distance,
+            });
+        }
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_sparse_voxels(
+    mut commands: Commands,
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
+    transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
+    sparse_pipeline: Res<pipeline::SparseVoxelPipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<pipeline::SparseVoxelPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    voxel_mesh: Res<voxel_mesh::VoxelMesh>,
+    sparse_data: Res<RenderAssets<SparseVoxelData>>,
+    mut instances: ResMut<VoxelInstances>,
+    voxels: Query<(&SparseVoxel, &MeshUniform)>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        &mut RenderPhase<AlphaMask3d>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
+) {
+    let draw_alpha_mask = alpha_mask_3d_draw_functions
+        .read()
+        .get_id::<draw::DrawSparseVoxels>()
+        .unwrap();
+    let draw_transparent = transparent_3d_draw_functions
+        .read()
+        .get_id::<draw::DrawSparseVoxels>()
+        .unwrap();
+
+    let mesh = match meshes.get(&voxel_mesh.mesh) {
+        Some(mesh) => mesh,
+        None => return,
+    };
+    let key = MeshPipelineKey::from_msaa_samples(msaa.samples)
+        | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+    let alpha_mask_pipeline = pipelines
+        .specialize(&mut pipeline_cache, &sparse_pipeline, key, &mesh.layout)
+        .unwrap();
+    let transparent_pipeline = pipelines
+        .specialize(
+            &mut pipeline_cache,
+            &sparse_pipeline,
+            key | MeshPipelineKey::TRANSPARENT_MAIN_PASS,
+            &mesh.layout,
+        )
+        .unwrap();
+
+    for (view, visible_entities, mut alpha_mask_phase, mut transparent_phase) in views.iter_mut() {
+        let rangefinder = view.rangefinder3d();
+        for visible_entity in visible_entities.iter() {
+            let (voxel, mesh_uniform) = match voxels.get(*visible_entity) {
+                Ok(voxel) => voxel,
+                Err(_) => continue,
+            };
+            let data = match sparse_data.get(&voxel.data) {
+                Some(data) => data,
+                None => continue,
+            };
+            let entity = match instances.push_sparse(&mut commands, voxel, data, mesh_uniform) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            let distance = rangefinder.distance(&mesh_uniform.transform);
+            if data.translucent {
+                transparent_phase.add(Transparent3d {
+                    entity,
+                    pipeline: transparent_pipeline,
+                    draw_function: draw_transparent,
+                    distance,
+                });
+            } else {
                 alpha_mask_phase.add(AlphaMask3d {
+                    entity,
+                    pipeline: alpha_mask_pipeline,
+                    draw_function: draw_alpha_mask,
+                    distance,
+                });
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_sparse_voxel_shadows(
+    mut commands: Commands,
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    shadow_pipeline: Res<pipeline::SparseVoxelShadowPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<pipeline::SparseVoxelShadowPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    voxel_mesh: Res<voxel_mesh::VoxelMesh>,
+    sparse_data: Res<RenderAssets<SparseVoxelData>>,
+    mut instances: ResMut<VoxelInstances>,
+    casting_voxels: Query<(&SparseVoxel, &MeshUniform), Without<NotShadowCaster>>,
+    mut shadow_views: ShadowViews,
+) {
+    let draw_shadow = shadow_draw_functions
+        .read()
+        .get_id::<draw::DrawSparseVoxelShadows>()
+        .unwrap();
+
+    let mesh = match meshes.get(&voxel_mesh.mesh) {
+        Some(mesh) => mesh,
+        None => return,
+    };
+    let key = ShadowPipelineKey::from_primitive_topology(mesh.primitive_topology);
+    let pipeline = pipelines
+        .specialize(&mut pipeline_cache, &shadow_pipeline, key, &mesh.layout)
+        .unwrap();
+
+    shadow_views.for_each(|view, visible_entities, shadow_phase| {
+        let rangefinder = view.rangefinder3d();
+        for visible_entity in visible_entities.iter() {
+            let (voxel, mesh_uniform) = match casting_voxels.get(*visible_entity) {
+                Ok(voxel) => voxel,
+                Err(_) => continue,
+            };
+            let entity = sparse_data
+                .get(&voxel.data)
+                .and_then(|data| instances.push_sparse(&mut commands, voxel, data, mesh_uniform));
+            if let Some(entity) = entity {
+                shadow_phase.add(Shadow {
                     entity,
                     pipeline,
-                    draw_function: draw_custom,
+                    draw_function: draw_shadow,
                     distance: rangefinder.distance(&mesh_uniform.transform),
                 });
-            };
-
-            visible_entities
-                .entities
-                .iter()
-                .filter_map(|visible_entity| voxels.get(*visible_entity).ok())
-                .for_each(add_render_phase);
+            }
         }
-    }
+    });
 }
@@ -0,0 +1,315 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use super::voxel::{Voxel, VoxelData};
+
+/// Closest cell hit by a [`VoxelRaycast`]
+#[derive(Clone, Debug)]
+pub struct VoxelRayHit {
+    pub entity: Entity,
+    /// Cell that was hit, in the grid of the entity's [`VoxelData`]
+    pub position: UVec3,
+    /// Face the ray entered the cell through, in grid space. Zero if the ray
+    /// started inside the cell.
+    pub normal: IVec3,
+    /// World space point where the ray entered the cell
+    pub point: Vec3,
+    /// World space distance from the ray origin to `point`
+    pub distance: f32,
+}
+
+impl VoxelRayHit {
+    /// Cell in front of the hit face, where a new voxel would be placed. May
+    /// be outside the grid.
+    pub fn adjacent(&self) -> IVec3 {
+        self.position.as_ivec3() + self.normal
+    }
+}
+
+/// Casts rays against the cells of every [`Voxel`] entity on the CPU.
+///
+/// Uses the same grid traversal as `voxel.wgsl`, any occupied cell stops the
+/// ray, including translucent ones.
+#[derive(SystemParam)]
+pub struct VoxelRaycast<'w, 's> {
+    assets: Res<'w, Assets<VoxelData>>,
+    voxels: Query<'w, 's, (Entity, &'static Voxel, &'static GlobalTransform)>,
+}
+
+impl<'w, 's> VoxelRaycast<'w, 's> {
+    /// Closest hit along the world space ray within `max_distance`
+    pub fn cast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<VoxelRayHit> {
+        self.cast_filtered(origin, direction, max_distance, |_| true)
+    }
+
+    /// Like [`VoxelRaycast::cast`], only testing entities for which `filter`
+    /// returns `true`
+    pub fn cast_filtered(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Option<VoxelRayHit> {
+        let direction = direction.try_normalize()?;
+        let mut closest: Option<VoxelRayHit> = None;
+        for (entity, voxel, transform) in self.voxels.iter() {
+            if !filter(entity) {
+                continue;
+            }
+            let data = match self.assets.get(&voxel.data) {
+                Some(data) => data,
+                None => continue,
+            };
+            let max_distance = closest.as_ref().map_or(max_distance, |hit| hit.distance);
+            if let Some((position, normal, distance)) =
+                cast_grid(data, transform, origin, direction, max_distance)
+            {
+                closest = Some(VoxelRayHit {
+                    entity,
+                    position,
+                    normal,
+                    point: origin + direction * distance,
+                    distance,
+                });
+            }
+        }
+        closest
+    }
+}
+
+/// Walks the cells of `data` along a world space ray with a normalized
+/// `direction`, returns the first occupied cell, its entry face and distance
+pub(crate) fn cast_grid(
+    data: &VoxelData,
+    transform: &GlobalTransform,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+) -> Option<(UVec3, IVec3, f32)> {
+    // Same mapping as the proxy in `voxel.wgsl`, `t` stays a world distance
+    let size = data.size();
+    if size.min_element() == 0 {
+        return None;
+    }
+    let max_size = size.max_element() as f32;
+    let to_object = transform.compute_matrix().inverse();
+    let grid_origin = to_object.transform_point3(origin) * max_size + size.as_vec3() * 0.5;
+    let grid_direction = to_object.transform_vector3(direction) * max_size;
+
+    // Slab test against the grid box
+    let inverse = grid_direction.recip();
+    let t0 = -grid_origin * inverse;
+    let t1 = (size.as_vec3() - grid_origin) * inverse;
+    let (near, far) = (t0.min(t1), t0.max(t1));
+    let t_enter = near.max_element();
+    let t_exit = far.min_element().min(max_distance);
+    if t_enter > t_exit || t_exit < 0.0 {
+        return None;
+    }
+
+    let step = IVec3::from(grid_direction.to_array().map(|axis| {
+        if axis > 0.0 {
+            1
+        } else if axis < 0.0 {
+            -1
+        } else {
+            0
+        }
+    }));
+    let mut t = t_enter.max(0.0);
+    let mut normal = IVec3::ZERO;
+    if t_enter > 0.0 {
+        let axis = (0..3).find(|&axis| near[axis] == t_enter).unwrap_or(0);
+        normal[axis] = -step[axis];
+    }
+
+    let start = grid_origin + grid_direction * t;
+    let mut position = start
+        .floor()
+        .as_ivec3()
+        .clamp(IVec3::ZERO, size.as_ivec3() - 1);
+    let t_delta = inverse.abs();
+    let mut t_max = Vec3::ZERO;
+    for axis in 0..3 {
+        let boundary = position[axis] + i32::from(step[axis] > 0);
+        t_max[axis] = if step[axis] == 0 {
+            f32::INFINITY
+        } else {
+            t + (boundary as f32 - start[axis]) * inverse[axis]
+        };
+    }
+
+    loop {
+        let cell = position.as_uvec3();
+        if data
+            .index(cell)
+            .map_or(false, |index| data.cells().is_occupied(index))
+        {
+            return Some((cell, normal, t));
+        }
+
+        let axis = if t_max.x < t_max.y {
+            if t_max.x < t_max.z {
+                0
+            } else {
+                2
+            }
+        } else if t_max.y < t_max.z {
+            1
+        } else {
+            2
+        };
+        position[axis] += step[axis];
+        if position[axis] < 0 || position[axis] >= size[axis] as i32 {
+            return None;
+        }
+        t = t_max[axis];
+        if t > t_exit {
+            return None;
+        }
+        normal = IVec3::ZERO;
+        normal[axis] = -step[axis];
+        t_max[axis] += t_delta[axis];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::cast_grid;
+    use crate::VoxelData;
+
+    mod duck {
+        include!("../examples/rubberduck/test_model.rs");
+    }
+
+    fn duck() -> VoxelData {
+        VoxelData::from_data(UVec3::splat(16), duck::TEST_MODEL_DUCK.to_vec())
+    }
+
+    /// World space point of a grid space one, for a 16³ grid drawn with the
+    /// identity transform
+    fn world(grid: Vec3) -> Vec3 {
+        (grid - 8.0) / 16.0
+    }
+
+    fn cast(data: &VoxelData, origin: Vec3, direction: Vec3) -> Option<(UVec3, IVec3, f32)> {
+        let direction = direction.normalize();
+        cast_grid(data, &GlobalTransform::identity(), origin, direction, 100.0)
+    }
+
+    /// First occupied cell along a ray found by intersecting the box of every
+    /// occupied cell, and the distance the ray enters it at
+    fn closest_cell(data: &VoxelData, origin: Vec3, direction: Vec3) -> Option<(UVec3, f32)> {
+        let direction = direction.normalize();
+        let grid_origin = origin * 16.0 + 8.0;
+        let inverse = (direction * 16.0).recip();
+        data.iter()
+            .filter_map(|(cell, _)| {
+                let t0 = (cell.as_vec3() - grid_origin) * inverse;
+                let t1 = (cell.as_vec3() + 1.0 - grid_origin) * inverse;
+                let near = t0.min(t1).max_element();
+                let far = t0.max(t1).min_element();
+                (near <= far && far >= 0.0).then(|| (cell, near.max(0.0)))
+            })
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    }
+
+    #[test]
+    fn hits_top_face_from_above() {
+        let data = duck();
+        let origin = world(Vec3::new(7.5, 20.0, 5.5));
+        let (position, normal, distance) = cast(&data, origin, -Vec3::Y).unwrap();
+        assert_eq!(position, UVec3::new(7, 7, 5));
+        assert_eq!(normal, IVec3::Y);
+        assert!((distance - 0.75).abs() < 1e-5);
+        let hit = super::VoxelRayHit {
+            entity: Entity::from_raw(0),
+            position,
+            normal,
+            point: origin - Vec3::Y * distance,
+            distance,
+        };
+        assert_eq!(hit.adjacent(), IVec3::new(7, 8, 5));
+    }
+
+    #[test]
+    fn hits_side_face_along_an_axis() {
+        let data = duck();
+        let origin = world(Vec3::new(-4.0, 0.5, 0.5));
+        let (position, normal, distance) = cast(&data, origin, Vec3::X).unwrap();
+        assert_eq!(position, UVec3::ZERO);
+        assert_eq!(normal, -IVec3::X);
+        assert!((distance - 0.25).abs() < 1e-5);
+
+        // Along the head from the other side, the dark eye is hit first
+        let origin = world(Vec3::new(20.0, 7.5, 5.5));
+        let (position, normal, _) = cast(&data, origin, -Vec3::X).unwrap();
+        assert_eq!(position, UVec3::new(9, 7, 5));
+        assert_eq!(normal, IVec3::X);
+    }
+
+    #[test]
+    fn starts_inside_the_grid() {
+        let data = duck();
+        // From an empty cell above the body
+        let origin = world(Vec3::new(7.5, 10.5, 7.5));
+        let (position, normal, distance) = cast(&data, origin, -Vec3::Y).unwrap();
+        assert_eq!(position, UVec3::new(7, 7, 7));
+        assert_eq!(normal, IVec3::Y);
+        assert!((distance - 2.5 / 16.0).abs() < 1e-5);
+
+        // From inside an occupied cell, which is hit right away
+        let origin = world(Vec3::new(7.5, 0.5, 7.5));
+        let (position, normal, distance) = cast(&data, origin, Vec3::new(1.0, 0.3, 0.2)).unwrap();
+        assert_eq!(position, UVec3::new(7, 0, 7));
+        assert_eq!(normal, IVec3::ZERO);
+        assert_eq!(distance, 0.0);
+    }
+
+    #[test]
+    fn misses() {
+        let data = duck();
+        // Through the empty top layers
+        assert!(cast(&data, world(Vec3::new(-4.0, 12.5, 7.5)), Vec3::X).is_none());
+        // Pointing away from the grid
+        assert!(cast(&data, world(Vec3::new(7.5, 20.0, 7.5)), Vec3::Y).is_none());
+        // Beside the grid, parallel to it
+        assert!(cast(&data, world(Vec3::new(20.0, 4.5, 7.5)), Vec3::Z).is_none());
+        // Stopped short of the body
+        let origin = world(Vec3::new(7.5, 20.0, 7.5));
+        let hit = cast_grid(&data, &GlobalTransform::identity(), origin, -Vec3::Y, 0.5);
+        assert!(hit.is_none());
+    }
+
+    #[test]
+    fn agrees_with_cell_boxes() {
+        let data = duck();
+        let mut seed = 0x9E37_79B9_u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        let mut hits = 0;
+        for _ in 0..500 {
+            let origin = (Vec3::new(random(), random(), random()) - 0.5) * 3.0;
+            let target = Vec3::new(random(), random(), random()) - 0.5;
+            let direction = target - origin;
+            let expected = closest_cell(&data, origin, direction);
+            let hit = cast(&data, origin, direction);
+            match (hit, expected) {
+                (Some((position, _, distance)), Some((cell, t))) => {
+                    assert_eq!(position, cell, "ray from {origin} towards {target}");
+                    assert!((distance - t).abs() < 1e-4);
+                    hits += 1;
+                }
+                (None, None) => {}
+                _ => panic!("ray from {origin} towards {target}: {hit:?} != {expected:?}"),
+            }
+        }
+        assert!(hits > 0);
+    }
+}
@@ -1,39 +1,162 @@
-#import bevy_pbr::mesh_types
-#import bevy_pbr::mesh_view_bindings
-
-@group(1) @binding(0)
-var<uniform> mesh: Mesh;
+#ifdef SHADOW_PASS
+#import bevy_pbr::mesh_view_types
 
-// NOTE: Bindings must come before functions that use them!
-#import bevy_pbr::mesh_functions
+@group(0) @binding(0)
+var<uniform> view: View;
+#else
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_types
+#import bevy_pbr::pbr_types
+#endif
 
 struct Vertex {
     @location(0) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
+    @location(3) model_0: vec4<f32>,
+    @location(4) model_1: vec4<f32>,
+    @location(5) model_2: vec4<f32>,
+    @location(6) model_3: vec4<f32>,
+    @location(7) inverse_model_0: vec4<f32>,
+    @location(8) inverse_model_1: vec4<f32>,
+    @location(9) inverse_model_2: vec4<f32>,
+    @location(10) inverse_model_3: vec4<f32>,
+    // Grid size in xyz and cell format in w
+    @location(11) size_format: vec4<u32>,
+    // Start of the cells in the data buffer and of the palette, mesh flags in z
+    // and start of the occupancy pyramid in the data buffer in w, or the
+    // depth of the tree for sparse voxels
+    @location(12) offsets: vec4<u32>,
+    // Occupied box, minimum in the low and exclusive maximum in the high 16 bits
+    @location(13) bounds: vec4<u32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) vertex_position: vec3<f32>,
+    @location(1) ray_direction: vec3<f32>,
+    @location(2) @interpolate(flat) size_format: vec4<u32>,
+    @location(3) @interpolate(flat) offsets: vec4<u32>,
+    @location(4) @interpolate(flat) model_0: vec4<f32>,
+    @location(5) @interpolate(flat) model_1: vec4<f32>,
+    @location(6) @interpolate(flat) model_2: vec4<f32>,
+    @location(7) @interpolate(flat) model_3: vec4<f32>,
+    @location(8) normal: vec3<f32>,
+    @location(9) @interpolate(flat) bounds: vec4<u32>,
+};
+
+struct FragmentOutput {
+#ifndef SHADOW_PASS
+    @location(0) color: vec4<f32>,
+#endif
+    @builtin(frag_depth) depth: f32,
+};
+
+// Formats of the cells in the data buffer
+let FORMAT_COLOR: u32 = 0u;
+let FORMAT_INDEXED8: u32 = 1u;
+let FORMAT_INDEXED16: u32 = 2u;
+let FORMAT_TYPED: u32 = 3u;
+
+struct Voxel {
+    data: array<u32>,
 };
 
+@group(1) @binding(0)
+var<storage> voxel: Voxel;
+
+struct Palette {
+    colors: array<u32>,
+};
+
+@group(1) @binding(1)
+var<storage> palette: Palette;
+
+// Row of the voxel type table, also built for colored cells
+struct VoxelType {
+    base_color: vec4<f32>,
+    emissive: vec4<f32>,
+    perceptual_roughness: f32,
+    metallic: f32,
+};
+
+struct VoxelTypes {
+    types: array<VoxelType>,
+};
+
+@group(1) @binding(2)
+var<storage> voxel_types: VoxelTypes;
+
+// Layout of the instance being shaded, set at the start of the fragment
+var<private> grid_size: vec3<u32>;
+var<private> cell_format: u32;
+var<private> data_offset: u32;
+var<private> palette_offset: u32;
+var<private> occupancy_offset: u32;
+var<private> octree_depth: u32;
+var<private> instance_model: mat4x4<f32>;
+
+#ifndef SHADOW_PASS
+// The lighting functions expect the mesh uniform, voxels only need its flags
+var<private> mesh: Mesh;
+
+#import bevy_pbr::utils
+#import bevy_pbr::clustered_forward
+#import bevy_pbr::lighting
+#import bevy_pbr::shadows
+#import bevy_pbr::pbr_functions
+#endif
+
+// Maps a corner of the unit cube proxy onto the occupied box, the largest grid
+// dimension spans one unit
+fn proxy_position(position: vec3<f32>, size: vec3<u32>, bounds: vec4<u32>) -> vec3<f32> {
+    let bounds_min = vec3<f32>(bounds.xyz & vec3<u32>(0xFFFFu));
+    let bounds_max = vec3<f32>(bounds.xyz >> vec3<u32>(16u));
+    let grid_position = mix(bounds_min, bounds_max, position + 0.5);
+    let extent = vec3<f32>(size);
+    return (grid_position - extent * 0.5) / max(extent.x, max(extent.y, extent.z));
+}
+
 @vertex
 fn vertex(vertex: Vertex) -> VertexOutput {
+    let model = mat4x4<f32>(vertex.model_0, vertex.model_1, vertex.model_2, vertex.model_3);
+    let inverse_model = mat4x4<f32>(
+        vertex.inverse_model_0,
+        vertex.inverse_model_1,
+        vertex.inverse_model_2,
+        vertex.inverse_model_3,
+    );
+    let position = proxy_position(vertex.position, vertex.size_format.xyz, vertex.bounds);
+
     var out: VertexOutput;
-    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(vertex.position, 1.0));
-    out.vertex_position = vertex.position;
+    out.clip_position = view.view_proj * model * vec4<f32>(position, 1.0);
+    out.vertex_position = position;
+    // Orthographic views, like directional light shadows, cast parallel rays
+    if (view.projection[3].w == 1.0) {
+        out.ray_direction = (inverse_model * vec4<f32>(-view.view[2].xyz, 0.0)).xyz;
+    } else {
+        out.ray_direction = position - (inverse_model * vec4<f32>(view.world_position, 1.0)).xyz;
+    }
+    out.size_format = vertex.size_format;
+    out.offsets = vertex.offsets;
+    out.model_0 = vertex.model_0;
+    out.model_1 = vertex.model_1;
+    out.model_2 = vertex.model_2;
+    out.model_3 = vertex.model_3;
+    out.normal = vertex.normal;
+    out.bounds = vertex.bounds;
     return out;
 }
 
-struct Voxel {
-    data: array<u32, 4096>,
-};
-
-@group(2) @binding(0)
-var<storage> voxel: Voxel;
+fn to_color(cell: u32) -> vec4<f32> {
+    var byte = cell;
+    // Sparse grids only bind their tree, which stores colors
+#ifndef VOXEL_SPARSE
+    if (cell_format != FORMAT_COLOR) {
+        byte = palette.colors[palette_offset + cell];
+    }
+#endif
 
-fn to_color(byte: u32) -> vec4<f32> {
     let a = (byte >> 24u) & 0xFFu;
     let r = (byte >> 16u) & 0xFFu;
     let g = (byte >> 8u) & 0xFFu;
@@ -42,10 +165,89 @@ fn to_color(byte: u32) -> vec4<f32> {
     return vec4<f32>(f32(r) / 255.0, f32(g) / 255.0, f32(b) / 255.0, f32(a) / 255.0);
 }
 
-fn get_color(vpos: vec3<i32>) -> vec4<f32> {
+fn get_cell(idx: u32) -> u32 {
+    if (cell_format == FORMAT_INDEXED8) {
+        return (voxel.data[data_offset + idx / 4u] >> ((idx % 4u) * 8u)) & 0xFFu;
+    }
+    if (cell_format == FORMAT_INDEXED16 || cell_format == FORMAT_TYPED) {
+        return (voxel.data[data_offset + idx / 2u] >> ((idx % 2u) * 16u)) & 0xFFFFu;
+    }
+    return voxel.data[data_offset + idx];
+}
+
+// Color of the cell at `vpos` in the tree of a sparse grid in x, or zero if
+// it is empty, along with the log2 of the edge of the largest empty node
+// around it in y. See `SparseVoxelData::nodes` for the encoding.
+fn octree_lookup(vpos: vec3<i32>) -> vec2<u32> {
     let uvpos = vec3<u32>(vpos);
-    let idx = uvpos.x + uvpos.z * 16u + uvpos.y * 256u;
-    return to_color(voxel.data[idx]);
+    var node = voxel.data[0];
+    var level = octree_depth;
+    loop {
+        level = level - 1u;
+        let bit = (uvpos >> vec3<u32>(level)) & vec3<u32>(1u);
+        let octant = bit.x | (bit.y << 1u) | (bit.z << 2u);
+        let mask = node & 0xFFu;
+        if ((mask & (1u << octant)) == 0u) {
+            return vec2<u32>(0u, level);
+        }
+        let child = (node >> 8u) + countOneBits(mask & ((1u << octant) - 1u));
+        if (level == 0u) {
+            return vec2<u32>(voxel.data[child], 0u);
+        }
+        node = voxel.data[child];
+    }
+    return vec2<u32>(0u);
+}
+
+fn get_voxel(vpos: vec3<i32>) -> VoxelType {
+#ifdef VOXEL_SPARSE
+    let cell = octree_lookup(vpos).x;
+#else
+    let uvpos = vec3<u32>(vpos);
+    let idx = uvpos.x + uvpos.z * grid_size.x + uvpos.y * grid_size.x * grid_size.z;
+    let cell = get_cell(idx);
+    if (cell_format == FORMAT_TYPED) {
+        return voxel_types.types[cell];
+    }
+#endif
+
+    // Colored cells get the defaults of `StandardMaterial`
+    var voxel_type: VoxelType;
+    voxel_type.base_color = to_color(cell);
+    voxel_type.emissive = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    voxel_type.perceptual_roughness = 0.089;
+    voxel_type.metallic = 0.01;
+    return voxel_type;
+}
+
+// Whether the brick of `1 << shift` cells around `vpos` can hold occupied
+// cells, the level starts `start` words into the occupancy pyramid
+fn brick_occupied(vpos: vec3<i32>, shift: u32, start: u32) -> bool {
+    let shifts = vec3<u32>(shift);
+    let brick = vec3<u32>(vpos) >> shifts;
+    let bricks = (grid_size + vec3<u32>((1u << shift) - 1u)) >> shifts;
+    let bit = brick.x + brick.z * bricks.x + brick.y * bricks.x * bricks.z;
+    return ((voxel.data[occupancy_offset + start + bit / 32u] >> (bit % 32u)) & 1u) != 0u;
+}
+
+// Log2 of the edge of the largest empty brick around `vpos`, zero when its
+// cell has to be read
+fn empty_brick_shift(vpos: vec3<i32>) -> u32 {
+    let fine = (grid_size + vec3<u32>(1u)) >> vec3<u32>(1u);
+    let coarse_start = (fine.x * fine.y * fine.z + 31u) / 32u;
+    if (!brick_occupied(vpos, 2u, coarse_start)) {
+        return 2u;
+    }
+    if (!brick_occupied(vpos, 1u, 0u)) {
+        return 1u;
+    }
+    return 0u;
+}
+
+// Blue for few traversal steps up to red for many
+fn iteration_heat(iterations: u32) -> vec4<f32> {
+    let heat = clamp(f32(iterations) / 128.0, 0.0, 1.0);
+    return vec4<f32>(heat, 1.0 - abs(2.0 * heat - 1.0), 1.0 - heat, 1.0);
 }
 
 fn intersect_plane_t(p: vec3<f32>, dir: vec3<f32>, plane: vec3<f32>) -> f32 {
@@ -60,55 +262,269 @@ fn intersect_plane_t(p: vec3<f32>, dir: vec3<f32>, plane: vec3<f32>) -> f32 {
     return 1000.0;
 }
 
-@fragment
-fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-    let obj_space_camera_pos = (vec4<f32>(view.world_position, 1.0) * mesh.inverse_transpose_model).xyz;
-    let view_dir = normalize(in.vertex_position - obj_space_camera_pos);
-    var vpos = vec3<i32>((in.vertex_position + 0.5) * 15.9999);
-    var color = get_color(vpos);
-
-    if (color.a > 0.5) {
-        return vec4<f32>(color.rgb, 1.0);
+// Depth of a hit at `position` in object space, written instead of the proxy
+// surface depth so voxels intersect other geometry correctly
+fn depth_at(position: vec3<f32>) -> f32 {
+    let clip = view.view_proj * instance_model * vec4<f32>(position, 1.0);
+    return clip.z / clip.w;
+}
+
+fn is_occupied(vpos: vec3<i32>) -> bool {
+    if (any(vpos < vec3<i32>(0)) || any(vpos >= vec3<i32>(grid_size))) {
+        return false;
+    }
+    return get_voxel(vpos).base_color.a > 0.5;
+}
+
+// Occlusion of one face corner by its two side neighbors and the diagonal one
+fn corner_occlusion(side_u: bool, side_v: bool, corner: bool) -> f32 {
+    if (side_u && side_v) {
+        return 0.0;
     }
+    return 3.0 - f32(side_u) - f32(side_v) - f32(corner);
+}
+
+// Classic per-vertex voxel AO of the face of `vpos` facing `normal`,
+// interpolated at `grid_hit`, the hit position in grid space
+fn ambient_occlusion(vpos: vec3<i32>, normal: vec3<f32>, grid_hit: vec3<f32>) -> f32 {
+#ifdef VOXEL_AMBIENT_OCCLUSION
+    let n = vec3<i32>(round(normal));
+    var u = vec3<i32>(1, 0, 0);
+    var v = vec3<i32>(0, 0, 1);
+    if (n.x != 0) {
+        u = vec3<i32>(0, 1, 0);
+    } else if (n.z != 0) {
+        v = vec3<i32>(0, 1, 0);
+    }
+
+    // Neighbors in the layer the face looks into
+    let layer = vpos + n;
+    let u_neg = is_occupied(layer - u);
+    let u_pos = is_occupied(layer + u);
+    let v_neg = is_occupied(layer - v);
+    let v_pos = is_occupied(layer + v);
+    let ao_00 = corner_occlusion(u_neg, v_neg, is_occupied(layer - u - v));
+    let ao_10 = corner_occlusion(u_pos, v_neg, is_occupied(layer + u - v));
+    let ao_01 = corner_occlusion(u_neg, v_pos, is_occupied(layer - u + v));
+    let ao_11 = corner_occlusion(u_pos, v_pos, is_occupied(layer + u + v));
+
+    let f = clamp(grid_hit - vec3<f32>(vpos), vec3<f32>(0.0), vec3<f32>(1.0));
+    let fu = dot(f, vec3<f32>(u));
+    let fv = dot(f, vec3<f32>(v));
+    let ao = mix(mix(ao_00, ao_10, fu), mix(ao_01, ao_11, fu), fv);
+    return 0.4 + 0.6 * ao / 3.0;
+#else
+    return 1.0;
+#endif
+}
+
+#ifndef SHADOW_PASS
+// Lights a voxel face hit at `position` in object space, alpha is the opacity
+// of the voxel type
+fn shade(
+    voxel_type: VoxelType,
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    frag_coord: vec4<f32>,
+    occlusion: f32,
+) -> vec4<f32> {
+    let world_position = instance_model * vec4<f32>(position, 1.0);
+
+    // Inverse transpose of a scaled rotation, skipping the full matrix inverse
+    let scale = vec3<f32>(
+        length(instance_model[0].xyz),
+        length(instance_model[1].xyz),
+        length(instance_model[2].xyz),
+    );
+    let model_3x3 = mat3x3<f32>(
+        instance_model[0].xyz,
+        instance_model[1].xyz,
+        instance_model[2].xyz,
+    );
+    let world_normal = normalize(model_3x3 * (normal / (scale * scale)));
+
+    var pbr_input = pbr_input_new();
+    pbr_input.material.base_color = vec4<f32>(voxel_type.base_color.rgb, 1.0);
+    // Added below so occlusion doesn't darken it
+    pbr_input.material.emissive = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    pbr_input.material.perceptual_roughness = voxel_type.perceptual_roughness;
+    pbr_input.material.metallic = voxel_type.metallic;
+    pbr_input.frag_coord = frag_coord;
+    pbr_input.world_position = world_position;
+    pbr_input.world_normal = world_normal;
+    pbr_input.N = world_normal;
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+    pbr_input.V = calculate_view(world_position, pbr_input.is_orthographic);
+
+    // Tone mapped like StandardMaterial so both draw modes match
+    let lit = pbr(pbr_input).rgb * occlusion;
+    return tone_mapping(vec4<f32>(lit + voxel_type.emissive.rgb, voxel_type.base_color.a));
+}
+#endif
+
+@fragment
+fn fragment(in: VertexOutput) -> FragmentOutput {
+    grid_size = in.size_format.xyz;
+    cell_format = in.size_format.w;
+    data_offset = in.offsets.x;
+    palette_offset = in.offsets.y;
+    occupancy_offset = in.offsets.w;
+    octree_depth = in.offsets.w;
+    instance_model = mat4x4<f32>(in.model_0, in.model_1, in.model_2, in.model_3);
+#ifndef SHADOW_PASS
+    mesh.flags = in.offsets.z;
+#endif
+
+    let view_dir = normalize(in.ray_direction);
+    let size = vec3<i32>(grid_size);
+    let max_size = f32(max(size.x, max(size.y, size.z)));
+    let grid_pos = in.vertex_position * max_size + vec3<f32>(size) * 0.5;
+    // The ray starts on the occupied box and leaves the grid once it leaves the box
+    let bounds_min = vec3<i32>(in.bounds.xyz & vec3<u32>(0xFFFFu));
+    let bounds_max = vec3<i32>(in.bounds.xyz >> vec3<u32>(16u));
+    var vpos = clamp(vec3<i32>(floor(grid_pos)), bounds_min, bounds_max - 1);
 
     let vsign = sign(view_dir);
     let step = vec3<i32>(vsign);
     let t_delta = 1.0 / abs(view_dir);
 
-    let sub_pos = 2.0 * ((in.vertex_position + 0.5) * 16.0 - vec3<f32>(vpos)) - 1.0;
+    let sub_pos = 2.0 * (grid_pos - vec3<f32>(vpos)) - 1.0;
     var t_max = 0.5 * vec3<f32>(
         intersect_plane_t(sub_pos, view_dir, vec3<f32>(vsign.x, 0.0, 0.0)),
         intersect_plane_t(sub_pos, view_dir, vec3<f32>(0.0, vsign.y, 0.0)),
         intersect_plane_t(sub_pos, view_dir, vec3<f32>(0.0, 0.0, vsign.z)),
     );
+    // Distance along the ray, in cells, to where it entered the current one,
+    // and the normal of the face it entered through
+    var t = 0.0;
+    var normal = in.normal;
+
+    var out: FragmentOutput;
+#ifdef VOXEL_TRANSPARENT
+    // Premultiplied color of the cells passed through, front to back
+    var accumulated = vec4<f32>(0.0);
+#endif
+#ifdef VOXEL_ITERATION_HEATMAP
+    var iterations = 0u;
+#endif
 
     loop {
+#ifdef VOXEL_ITERATION_HEATMAP
+        iterations = iterations + 1u;
+#endif
+#ifdef VOXEL_SPARSE
+        // Empty nodes of the tree are skipped like empty bricks
+        let shift = octree_lookup(vpos).y;
+#else
+#ifdef VOXEL_SKIP_EMPTY
+        let shift = empty_brick_shift(vpos);
+#else
+        let shift = 0u;
+#endif
+#endif
+        if (shift > 0u) {
+            // Cells left in the brick along each axis, and where the ray
+            // leaves it through each axis
+            let brick_min = (vpos >> vec3<u32>(shift)) << vec3<u32>(shift);
+            let brick_max = brick_min + vec3<i32>((1 << shift) - 1);
+            let remaining = select(vpos - brick_min, brick_max - vpos, step > vec3<i32>(0));
+            // Rays parallel to an axis never leave through it
+            let parallel = step == vec3<i32>(0);
+            let t_exit = select(t_max + vec3<f32>(remaining) * t_delta, vec3<f32>(1e30), parallel);
+            var axis = 0;
+            if (t_exit.y < t_exit[axis]) { axis = 1; }
+            if (t_exit.z < t_exit[axis]) { axis = 2; }
+            let t_jump = t_exit[axis];
+
+            // Cell boundaries the ray crosses until it leaves the brick
+            var crossed = select(
+                vec3<i32>(0),
+                vec3<i32>(floor((t_jump - t_max) / t_delta)) + 1,
+                t_max < vec3<f32>(t_jump),
+            );
+            crossed = select(crossed, vec3<i32>(0), parallel);
+            crossed[axis] = remaining[axis] + 1;
+            vpos = vpos + step * crossed;
+            t_max = select(t_max + vec3<f32>(crossed) * t_delta, t_max, crossed == vec3<i32>(0));
+            t = t_jump;
+            normal = vec3<f32>(0.0);
+            normal[axis] = -vsign[axis];
+            if (any(vpos < bounds_min) || any(vpos >= bounds_max)) {
+                break;
+            }
+            continue;
+        }
+        let voxel_type = get_voxel(vpos);
+        let position = in.vertex_position + view_dir * t / max_size;
+#ifdef VOXEL_TRANSPARENT
+        if (voxel_type.base_color.a > 0.0) {
+            if (accumulated.a == 0.0) {
+                out.depth = depth_at(position);
+            }
+            let occlusion = ambient_occlusion(vpos, normal, grid_pos + view_dir * t);
+            let color = shade(voxel_type, position, normal, in.clip_position, occlusion);
+            accumulated = accumulated + (1.0 - accumulated.a) * vec4<f32>(color.rgb * color.a, color.a);
+            // Nothing behind is visible anymore
+            if (accumulated.a > 0.99) {
+                break;
+            }
+        }
+#else
+        if (voxel_type.base_color.a > 0.5) {
+            out.depth = depth_at(position);
+#ifndef SHADOW_PASS
+            let occlusion = ambient_occlusion(vpos, normal, grid_pos + view_dir * t);
+            let color = shade(voxel_type, position, normal, in.clip_position, occlusion);
+            out.color = vec4<f32>(color.rgb, 1.0);
+#ifdef VOXEL_ITERATION_HEATMAP
+            out.color = iteration_heat(iterations);
+#endif
+#endif
+            return out;
+        }
+#endif
+
         if (t_max.x < t_max.y) {
             if (t_max.x < t_max.z) {
                 vpos.x = vpos.x + step.x;
-                if (vpos.x < 0 || vpos.x >= 16) { break; }
+                if (vpos.x < bounds_min.x || vpos.x >= bounds_max.x) { break; }
+                t = t_max.x;
+                normal = vec3<f32>(-vsign.x, 0.0, 0.0);
                 t_max.x = t_max.x + t_delta.x;
             } else {
                 vpos.z = vpos.z + step.z;
-                if (vpos.z < 0 || vpos.z >= 16) { break; }
+                if (vpos.z < bounds_min.z || vpos.z >= bounds_max.z) { break; }
+                t = t_max.z;
+                normal = vec3<f32>(0.0, 0.0, -vsign.z);
                 t_max.z = t_max.z + t_delta.z;
             }
         } else {
             if (t_max.y < t_max.z) {
                 vpos.y = vpos.y + step.y;
-                if (vpos.y < 0 || vpos.y >= 16) { break; }
+                if (vpos.y < bounds_min.y || vpos.y >= bounds_max.y) { break; }
+                t = t_max.y;
+                normal = vec3<f32>(0.0, -vsign.y, 0.0);
                 t_max.y = t_max.y + t_delta.y;
             } else {
                 vpos.z = vpos.z + step.z;
-                if (vpos.z < 0 || vpos.z >= 16) { break; }
+                if (vpos.z < bounds_min.z || vpos.z >= bounds_max.z) { break; }
+                t = t_max.z;
+                normal = vec3<f32>(0.0, 0.0, -vsign.z);
                 t_max.z = t_max.z + t_delta.z;
             }
         }
-        color = get_color(vpos);
+    }
 
-        if (color.a > 0.5) {
-            return vec4<f32>(color.rgb, 1.0);
-        }
+#ifdef VOXEL_ITERATION_HEATMAP
+    // Misses are shaded too, they often take the most steps
+    out.color = iteration_heat(iterations);
+    return out;
+#endif
+#ifdef VOXEL_TRANSPARENT
+    if (accumulated.a > 0.0) {
+        out.color = vec4<f32>(accumulated.rgb / accumulated.a, accumulated.a);
+        return out;
     }
+#endif
     discard;
 }
@@ -1,24 +1,13 @@
-#import bevy_pbr::mesh_types
 #import bevy_pbr::mesh_view_bindings
 
-@group(1) @binding(0)
-var<uniform> mesh: Mesh;
-
-#ifdef SKINNED
-@group(1) @binding(1)
-var<uniform> joint_matrices: SkinnedMesh;
-#import bevy_pbr::skinning
-#endif
-
-// NOTE: Bindings must come before functions that use them!
-#import bevy_pbr::mesh_functions
-
 struct Vertex {
     @location(0) position: vec3<f32>,
-#ifdef SKINNED
-    @location(4) joint_indexes: vec4<u32>,
-    @location(5) joint_weights: vec4<f32>,
-#endif
+    @location(3) model_0: vec4<f32>,
+    @location(4) model_1: vec4<f32>,
+    @location(5) model_2: vec4<f32>,
+    @location(6) model_3: vec4<f32>,
+    @location(11) size_format: vec4<u32>,
+    @location(13) bounds: vec4<u32>,
 };
 
 struct VertexOutput {
@@ -27,14 +16,16 @@ struct VertexOutput {
 
 @vertex
 fn vertex(vertex: Vertex) -> VertexOutput {
-#ifdef SKINNED
-    let model = skin_model(vertex.joint_indexes, vertex.joint_weights);
-#else
-    let model = mesh.model;
-#endif
+    let model = mat4x4<f32>(vertex.model_0, vertex.model_1, vertex.model_2, vertex.model_3);
+    // Outlines the occupied box, see `proxy_position` in voxel.wgsl
+    let bounds_min = vec3<f32>(vertex.bounds.xyz & vec3<u32>(0xFFFFu));
+    let bounds_max = vec3<f32>(vertex.bounds.xyz >> vec3<u32>(16u));
+    let size = vec3<f32>(vertex.size_format.xyz);
+    let grid_position = mix(bounds_min, bounds_max, vertex.position + 0.5);
+    let position = (grid_position - size * 0.5) / max(size.x, max(size.y, size.z));
 
     var out: VertexOutput;
-    out.clip_position = mesh_position_local_to_clip(model, vec4<f32>(vertex.position, 1.0));
+    out.clip_position = view.view_proj * model * vec4<f32>(position, 1.0);
     return out;
 }
 
@@ -0,0 +1,405 @@
+use bevy::{
+    core::cast_slice,
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_component::ExtractComponent,
+        render_asset::{PrepareAssetError, RenderAsset},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BufferInitDescriptor, BufferUsages,
+        },
+        renderer::RenderDevice,
+    },
+};
+
+use super::{
+    pipeline::SparseVoxelPipeline,
+    voxel::{VoxelCells, VoxelData, VoxelDataError},
+};
+
+/// Largest number of words a tree can address, child pointers take the 24
+/// high bits of a node
+const MAX_NODE_WORDS: usize = 1 << 24;
+
+/// Voxels stored in an octree, for large grids that are mostly empty.
+///
+/// Only occupied branches are stored, so memory grows with the number of
+/// occupied cells rather than the volume. Cells are ARGB colors, the tree
+/// can't be edited once built.
+#[derive(TypeUuid, Clone)]
+#[uuid = "5a0b7b3e-2f53-4d8f-9a47-b3c1e8d6f412"]
+pub struct SparseVoxelData {
+    size: UVec3,
+    depth: u32,
+    nodes: Vec<u32>,
+    bounds: Option<(UVec3, UVec3)>,
+    translucent: bool,
+}
+
+/// Draws a [`SparseVoxelData`] like [`Voxel`](crate::Voxel) draws dense grids
+#[derive(Component, Clone, Default)]
+pub struct SparseVoxel {
+    pub data: Handle<SparseVoxelData>,
+}
+
+impl SparseVoxelData {
+    /// Builds the tree of a grid of the given dimensions from colored cells.
+    /// Later cells replace earlier ones at the same position, fully
+    /// transparent ones empty it.
+    ///
+    /// Fails if a dimension is over 32768 or the tree takes more than 2²⁴ words.
+    pub fn from_voxels(
+        size: UVec3,
+        voxels: impl IntoIterator<Item = (UVec3, u32)>,
+    ) -> Result<Self, VoxelDataError> {
+        if size.max_element() > 1 << 15 {
+            return Err(VoxelDataError::TooLarge(size));
+        }
+        let depth = (32 - size.max_element().saturating_sub(1).leading_zeros()).max(1);
+
+        // Children of every node, node indices until the last level where
+        // they are colors. Zero is empty, the root is never anyone's child.
+        let mut tree = vec![[0u32; 8]];
+        let mut path = Vec::with_capacity(depth as usize);
+        'voxels: for (position, color) in voxels {
+            if position.cmpge(size).any() {
+                return Err(VoxelDataError::OutOfBounds { position, size });
+            }
+            let empty = color >> 24 == 0;
+            path.clear();
+            let mut node = 0;
+            for level in (1..depth).rev() {
+                let octant = octant(position, level);
+                if tree[node][octant] == 0 {
+                    if empty {
+                        continue 'voxels;
+                    }
+                    tree[node][octant] = tree.len() as u32;
+                    tree.push([0; 8]);
+                }
+                path.push((node, octant));
+                node = tree[node][octant] as usize;
+            }
+            tree[node][octant(position, 0)] = if empty { 0 } else { color };
+
+            // Unlink branches left empty, so they aren't encoded
+            if empty {
+                for &(parent, octant) in path.iter().rev() {
+                    if tree[node] != [0; 8] {
+                        break;
+                    }
+                    tree[parent][octant] = 0;
+                    node = parent;
+                }
+            }
+        }
+
+        let mut nodes = vec![0];
+        encode(&tree, 0, 0, depth, &mut nodes);
+        if nodes.len() > MAX_NODE_WORDS {
+            return Err(VoxelDataError::TooLarge(size));
+        }
+        let mut bounds = None;
+        let mut translucent = false;
+        summarize(
+            &tree,
+            0,
+            UVec3::ZERO,
+            depth - 1,
+            &mut bounds,
+            &mut translucent,
+        );
+        Ok(Self {
+            size,
+            depth,
+            nodes,
+            bounds,
+            translucent,
+        })
+    }
+
+    /// Grid dimensions: `x` is width, `y` is height and `z` is depth
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    /// Levels below the root, the tree covers a cube of `1 << depth` cells
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// The encoded tree, as uploaded to the GPU.
+    ///
+    /// The root is the first word. A node stores which of its eight octants
+    /// are occupied in its low 8 bits, octant bits being `x | y << 1 | z << 2`,
+    /// and the index of its first child in the high 24 bits. Children of
+    /// occupied octants follow each other in octant order, the ones on the
+    /// last level are ARGB colors instead of nodes.
+    pub fn nodes(&self) -> &[u32] {
+        &self.nodes
+    }
+
+    /// Occupied box in cells, `max` exclusive, `None` if the grid is empty
+    pub fn bounds(&self) -> Option<(UVec3, UVec3)> {
+        self.bounds
+    }
+
+    /// ARGB color of the cell at `position`, zero if it is empty
+    pub fn get(&self, position: UVec3) -> Result<u32, VoxelDataError> {
+        if position.cmpge(self.size).any() {
+            return Err(VoxelDataError::OutOfBounds {
+                position,
+                size: self.size,
+            });
+        }
+        let mut node = self.nodes[0];
+        for level in (0..self.depth).rev() {
+            let octant = octant(position, level);
+            let mask = node & 0xFF;
+            if mask & (1 << octant) == 0 {
+                return Ok(0);
+            }
+            let child = (node >> 8) + (mask & ((1 << octant) - 1)).count_ones();
+            node = self.nodes[child as usize];
+        }
+        Ok(node)
+    }
+}
+
+impl TryFrom<&VoxelData> for SparseVoxelData {
+    type Error = VoxelDataError;
+
+    /// Stores the colors of a dense grid, typed grids have no colors and are
+    /// rejected
+    fn try_from(data: &VoxelData) -> Result<Self, Self::Error> {
+        if matches!(data.cells(), VoxelCells::Typed(_)) {
+            return Err(VoxelDataError::FormatMismatch);
+        }
+        Self::from_voxels(data.size(), data.iter())
+    }
+}
+
+/// Octant of the child containing `position` below a node whose children
+/// span `1 << level` cells
+fn octant(position: UVec3, level: u32) -> usize {
+    let bit = (position >> level) & UVec3::ONE;
+    (bit.x | bit.y << 1 | bit.z << 2) as usize
+}
+
+/// Widens `bounds` to the cells below `node`, whose children span
+/// `1 << level` cells each from `origin`, and notes see-through ones
+fn summarize(
+    tree: &[[u32; 8]],
+    node: usize,
+    origin: UVec3,
+    level: u32,
+    bounds: &mut Option<(UVec3, UVec3)>,
+    translucent: &mut bool,
+) {
+    for (octant, &child) in tree[node].iter().enumerate() {
+        if child == 0 {
+            continue;
+        }
+        let octant = octant as u32;
+        let bit = UVec3::new(octant & 1, octant >> 1 & 1, octant >> 2 & 1);
+        let origin = origin + (bit << level);
+        if level > 0 {
+            summarize(tree, child as usize, origin, level - 1, bounds, translucent);
+            continue;
+        }
+        *translucent |= child >> 24 != 0xFF;
+        *bounds = Some(match *bounds {
+            Some((min, max)) => (min.min(origin), max.max(origin + 1)),
+            None => (origin, origin + 1),
+        });
+    }
+}
+
+/// Writes the node at `at` and its children after the end of `nodes`, then
+/// their own children depth first
+fn encode(tree: &[[u32; 8]], node: usize, at: usize, level: u32, nodes: &mut Vec<u32>) {
+    let children = &tree[node];
+    let mask = (0..8)
+        .filter(|&octant| children[octant] != 0)
+        .fold(0, |mask, octant| mask | 1 << octant);
+    let first = nodes.len();
+    nodes[at] = mask | (first as u32) << 8;
+    nodes.extend(children.iter().filter(|&&child| child != 0));
+    if level > 1 {
+        for (i, &child) in children.iter().filter(|&&child| child != 0).enumerate() {
+            encode(tree, child as usize, first + i, level - 1, nodes);
+        }
+    }
+}
+
+impl ExtractComponent for SparseVoxel {
+    type Query = &'static Self;
+    type Filter = ();
+
+    fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Self {
+        item.clone()
+    }
+}
+
+/// Tree of a [`SparseVoxelData`] in its own storage buffer, bound by itself
+pub struct GpuSparseVoxelData {
+    pub(crate) bind_group: BindGroup,
+    pub(crate) size: UVec3,
+    pub(crate) depth: u32,
+    pub(crate) bounds: Option<(UVec3, UVec3)>,
+    /// Has see-through cells and must be drawn in the transparent phase
+    pub(crate) translucent: bool,
+}
+
+impl RenderAsset for SparseVoxelData {
+    type ExtractedAsset = SparseVoxelData;
+    type PreparedAsset = GpuSparseVoxelData;
+    type Param = (SRes<RenderDevice>, SRes<SparseVoxelPipeline>);
+
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.clone()
+    }
+
+    fn prepare_asset(
+        data: Self::ExtractedAsset,
+        (render_device, pipeline): &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("sparse voxel node buffer"),
+            contents: cast_slice(&data.nodes),
+            usage: BufferUsages::STORAGE,
+        });
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("sparse voxel bind group"),
+            layout: &pipeline.sparse_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+        Ok(GpuSparseVoxelData {
+            bind_group,
+            size: data.size,
+            depth: data.depth,
+            bounds: data.bounds,
+            translucent: data.translucent,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::SparseVoxelData;
+    use crate::{VoxelData, VoxelDataError};
+
+    /// Grid with about a third of its cells set, some of them see-through
+    fn random_grid(size: UVec3) -> VoxelData {
+        let mut data = VoxelData::new(size);
+        let mut seed = 0x2545_f491_u32;
+        for index in 0..(size.x * size.y * size.z) as usize {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let alpha = [0xFF, 0x80][(seed >> 8) as usize % 2];
+            if seed % 3 == 0 {
+                let color = alpha << 24 | seed >> 8 & 0xFF_FFFF;
+                data.set(data.position(index), color).unwrap();
+            }
+        }
+        data
+    }
+
+    /// Checks every cell and the bounds of `sparse` against `data`
+    fn assert_same(sparse: &SparseVoxelData, data: &VoxelData) {
+        let size = data.size();
+        for index in 0..(size.x * size.y * size.z) as usize {
+            let position = data.position(index);
+            let color = data.get(position).unwrap();
+            let expected = if color >> 24 == 0 { 0 } else { color };
+            assert_eq!(sparse.get(position).unwrap(), expected, "cell {position}");
+        }
+        assert_eq!(sparse.bounds(), data.bounds());
+    }
+
+    #[test]
+    fn round_trips_depth_one() {
+        for size in [UVec3::ONE, UVec3::new(2, 1, 2), UVec3::splat(2)] {
+            let data = random_grid(size);
+            let sparse = SparseVoxelData::try_from(&data).unwrap();
+            assert_eq!(sparse.depth(), 1);
+            assert_same(&sparse, &data);
+        }
+    }
+
+    #[test]
+    fn round_trips_non_power_of_two_sizes() {
+        for size in [
+            UVec3::new(5, 3, 7),
+            UVec3::new(17, 1, 9),
+            UVec3::new(3, 33, 6),
+        ] {
+            let data = random_grid(size);
+            let sparse = SparseVoxelData::try_from(&data).unwrap();
+            assert_eq!(1 << sparse.depth(), size.max_element().next_power_of_two());
+            assert_same(&sparse, &data);
+            assert!(sparse.get(size).is_err());
+        }
+    }
+
+    #[test]
+    fn round_trips_empty_grids() {
+        let data = VoxelData::new(UVec3::new(6, 4, 9));
+        let sparse = SparseVoxelData::try_from(&data).unwrap();
+        assert_eq!(sparse.nodes().len(), 1);
+        assert!(!sparse.translucent);
+        assert_same(&sparse, &data);
+    }
+
+    #[test]
+    fn transparent_cells_empty_earlier_ones() {
+        let cells = [
+            (UVec3::new(1, 2, 3), 0x80FF_0000),
+            (UVec3::new(6, 0, 1), 0xFF00_FF00),
+            (UVec3::new(1, 2, 3), 0x00FF_FFFF),
+        ];
+        let sparse = SparseVoxelData::from_voxels(UVec3::splat(8), cells).unwrap();
+        assert_eq!(sparse.get(UVec3::new(1, 2, 3)).unwrap(), 0);
+        assert_eq!(sparse.get(UVec3::new(6, 0, 1)).unwrap(), 0xFF00_FF00);
+        // Only the opaque cell is left, its branch alone is encoded
+        assert_eq!(
+            sparse.bounds(),
+            Some((UVec3::new(6, 0, 1), UVec3::new(7, 1, 2)))
+        );
+        assert!(!sparse.translucent);
+        assert_eq!(sparse.nodes().len(), 1 + 1 + 1 + 1);
+
+        let cleared = [(UVec3::ONE, 0xFFFF_FFFF), (UVec3::ONE, 0)];
+        let sparse = SparseVoxelData::from_voxels(UVec3::splat(8), cleared).unwrap();
+        assert_eq!(sparse.nodes().len(), 1);
+        assert_eq!(sparse.bounds(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_grids() {
+        let typed = VoxelData::with_types(UVec3::splat(4));
+        assert!(matches!(
+            SparseVoxelData::try_from(&typed),
+            Err(VoxelDataError::FormatMismatch)
+        ));
+
+        let size = UVec3::new(1 << 16, 1, 1);
+        assert!(matches!(
+            SparseVoxelData::from_voxels(size, std::iter::empty()),
+            Err(VoxelDataError::TooLarge(_))
+        ));
+
+        let outside = [(UVec3::new(4, 0, 0), 0xFFFF_FFFF)];
+        assert!(matches!(
+            SparseVoxelData::from_voxels(UVec3::splat(4), outside),
+            Err(VoxelDataError::OutOfBounds { .. })
+        ));
+    }
+}
@@ -0,0 +1,340 @@
+use std::ops::Range;
+
+use bevy::{
+    core::cast_slice,
+    prelude::*,
+    render::{
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, Buffer, BufferDescriptor,
+            BufferInitDescriptor, BufferUsages,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+    utils::HashMap,
+};
+use bytemuck::Zeroable;
+
+use super::{pipeline, voxel::VoxelData, voxel_type::GpuVoxelType};
+
+/// Location of the cells and palette of a [`VoxelData`] in [`VoxelStorage`]
+#[derive(Clone)]
+pub(crate) struct VoxelSlot {
+    pub(crate) size: UVec3,
+    pub(crate) format: u32,
+    /// Words in the combined data buffer, the cells followed by the
+    /// occupancy pyramid
+    pub(crate) data: Range<usize>,
+    /// Words of `data` holding cells
+    pub(crate) cells: usize,
+    /// Entries in the combined palette buffer
+    pub(crate) palette: Range<usize>,
+    /// Has see-through cells and must be drawn in the transparent phase
+    pub(crate) translucent: bool,
+    /// Occupied box in cells, `max` exclusive, empty grids aren't drawn
+    pub(crate) bounds: Option<(UVec3, UVec3)>,
+}
+
+/// Cells and palettes of every [`VoxelData`] packed into two storage buffers,
+/// so entities using different assets can share one instanced draw call.
+/// The voxel type table is bound next to them.
+///
+/// Each buffer is bound whole, so it can't outgrow the largest storage
+/// binding of the device. Assets that don't fit anymore aren't stored, and
+/// their entities aren't drawn.
+pub(crate) struct VoxelStorage {
+    slots: HashMap<Handle<VoxelData>, VoxelSlot>,
+    data: Vec<u32>,
+    palettes: Vec<u32>,
+    /// Words of `data` no slot points to anymore
+    unused: usize,
+    /// Entries of `palettes` no slot points to anymore
+    unused_palettes: usize,
+    /// Words one storage binding can hold
+    max_words: usize,
+    dirty_data: Vec<Range<usize>>,
+    dirty_palettes: Vec<Range<usize>>,
+    data_buffer: Option<Buffer>,
+    data_capacity: usize,
+    palette_buffer: Option<Buffer>,
+    palette_capacity: usize,
+    types: Vec<GpuVoxelType>,
+    types_buffer: Option<Buffer>,
+    pub(crate) bind_group: Option<BindGroup>,
+}
+
+impl FromWorld for VoxelStorage {
+    fn from_world(world: &mut World) -> Self {
+        let limits = world.resource::<RenderDevice>().limits();
+        Self {
+            slots: default(),
+            data: default(),
+            palettes: default(),
+            unused: 0,
+            unused_palettes: 0,
+            max_words: limits.max_storage_buffer_binding_size as usize / 4,
+            dirty_data: default(),
+            dirty_palettes: default(),
+            data_buffer: None,
+            data_capacity: 0,
+            palette_buffer: None,
+            palette_capacity: 0,
+            types: default(),
+            types_buffer: None,
+            bind_group: None,
+        }
+    }
+}
+
+impl VoxelStorage {
+    pub(crate) fn slot(&self, handle: &Handle<VoxelData>) -> Option<&VoxelSlot> {
+        self.slots.get(handle)
+    }
+
+    /// Stores all cells and the palette of `data`, reusing the previous
+    /// location of `handle` if the sizes still match
+    pub(crate) fn insert(&mut self, handle: Handle<VoxelData>, data: &VoxelData) {
+        let words = data.cells().words();
+        let len = words.len() + VoxelData::occupancy_words(data.size());
+        let palette = data.palette().unwrap_or_default();
+
+        let slot = match self.slots.get(&handle) {
+            Some(slot) if slot.data.len() == len && slot.palette.len() == palette.len() => {
+                VoxelSlot {
+                    cells: words.len(),
+                    size: data.size(),
+                    format: data.cells().format(),
+                    ..slot.clone()
+                }
+            }
+            _ => {
+                self.remove(&handle);
+                let fits = |storage: &Self| {
+                    storage.data.len() + len <= storage.max_words
+                        && storage.palettes.len() + palette.len() <= storage.max_words
+                };
+                if !fits(self) {
+                    self.compact();
+                }
+                if !fits(self) {
+                    warn!(
+                        "voxel storage is full, an asset of {} words isn't drawn",
+                        len
+                    );
+                    return;
+                }
+                let slot = VoxelSlot {
+                    size: data.size(),
+                    format: data.cells().format(),
+                    data: self.data.len()..self.data.len() + len,
+                    cells: words.len(),
+                    palette: self.palettes.len()..self.palettes.len() + palette.len(),
+                    translucent: false,
+                    bounds: None,
+                };
+                self.data.resize(slot.data.end, 0);
+                self.palettes.resize(slot.palette.end, 0);
+                slot
+            }
+        };
+
+        self.data[slot.data.start..slot.data.start + slot.cells].copy_from_slice(&words);
+        self.data[slot.data.start + slot.cells..slot.data.end].copy_from_slice(&data.occupancy());
+        self.palettes[slot.palette.clone()].copy_from_slice(palette);
+        self.dirty_data.push(slot.data.clone());
+        self.dirty_palettes.push(slot.palette.clone());
+        self.slots.insert(handle, slot);
+    }
+
+    /// Overwrites data words of `handle` starting at `offset` words into its slot
+    pub(crate) fn write_words(&mut self, handle: &Handle<VoxelData>, offset: usize, words: &[u32]) {
+        if let Some(slot) = self.slots.get(handle) {
+            let cells_end = slot.data.start + slot.cells;
+            let start = (slot.data.start + offset).min(cells_end);
+            let end = (start + words.len()).min(cells_end);
+            self.data[start..end].copy_from_slice(&words[..end - start]);
+            self.dirty_data.push(start..end);
+        }
+    }
+
+    /// Overwrites the palette of `handle`, which must not have changed its length
+    pub(crate) fn write_palette(&mut self, handle: &Handle<VoxelData>, palette: &[u32]) {
+        if let Some(slot) = self.slots.get(handle) {
+            let len = palette.len().min(slot.palette.len());
+            let range = slot.palette.start..slot.palette.start + len;
+            self.palettes[range.clone()].copy_from_slice(&palette[..len]);
+            self.dirty_palettes.push(range);
+        }
+    }
+
+    /// Overwrites words of the occupancy pyramid of `handle` starting at
+    /// `offset` words into the pyramid
+    pub(crate) fn write_occupancy(
+        &mut self,
+        handle: &Handle<VoxelData>,
+        offset: usize,
+        words: &[u32],
+    ) {
+        if let Some(slot) = self.slots.get(handle) {
+            let start = (slot.data.start + slot.cells + offset).min(slot.data.end);
+            let end = (start + words.len()).min(slot.data.end);
+            self.data[start..end].copy_from_slice(&words[..end - start]);
+            self.dirty_data.push(start..end);
+        }
+    }
+
+    pub(crate) fn set_translucent(&mut self, handle: &Handle<VoxelData>, translucent: bool) {
+        if let Some(slot) = self.slots.get_mut(handle) {
+            slot.translucent = translucent;
+        }
+    }
+
+    pub(crate) fn set_bounds(
+        &mut self,
+        handle: &Handle<VoxelData>,
+        bounds: Option<(UVec3, UVec3)>,
+    ) {
+        if let Some(slot) = self.slots.get_mut(handle) {
+            slot.bounds = bounds;
+        }
+    }
+
+    /// Replaces the voxel type table
+    pub(crate) fn write_types(&mut self, types: Vec<GpuVoxelType>) {
+        self.types = types;
+        self.types_buffer = None;
+    }
+
+    pub(crate) fn remove(&mut self, handle: &Handle<VoxelData>) {
+        if let Some(slot) = self.slots.remove(handle) {
+            self.unused += slot.data.len();
+            self.unused_palettes += slot.palette.len();
+        }
+    }
+
+    /// Uploads everything written since the last call, compacting and
+    /// growing the buffers when needed
+    pub(crate) fn upload(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        pipeline: &pipeline::VoxelPipeline,
+    ) {
+        if self.unused > self.data.len() / 2 || self.unused_palettes > self.palettes.len() / 2 {
+            self.compact();
+        }
+
+        let mut rebind = false;
+        if self.data_buffer.is_none()
+            || self.data.len() > self.data_capacity
+            || self.palettes.len() > self.palette_capacity
+        {
+            let capacity = |len: usize| len.max(1).next_power_of_two().min(self.max_words);
+            self.data_capacity = capacity(self.data.len());
+            self.palette_capacity = capacity(self.palettes.len());
+            self.data_buffer = Some(create_storage_buffer(
+                render_device,
+                "voxel data buffer",
+                self.data_capacity,
+            ));
+            self.palette_buffer = Some(create_storage_buffer(
+                render_device,
+                "voxel palette buffer",
+                self.palette_capacity,
+            ));
+            self.dirty_data = vec![0..self.data.len()];
+            self.dirty_palettes = vec![0..self.palettes.len()];
+            rebind = true;
+        }
+
+        if self.types_buffer.is_none() {
+            // Storage bindings can't be empty, air always gets a row
+            if self.types.is_empty() {
+                self.types.push(GpuVoxelType::zeroed());
+            }
+            self.types_buffer = Some(render_device.create_buffer_with_data(
+                &BufferInitDescriptor {
+                    label: Some("voxel type buffer"),
+                    contents: cast_slice(&self.types),
+                    usage: BufferUsages::STORAGE,
+                },
+            ));
+            rebind = true;
+        }
+
+        if let (true, Some(data), Some(palettes), Some(types)) = (
+            rebind,
+            &self.data_buffer,
+            &self.palette_buffer,
+            &self.types_buffer,
+        ) {
+            self.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("voxel data bind group"),
+                layout: &pipeline.voxel_data_bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: data.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: palettes.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: types.as_entire_binding(),
+                    },
+                ],
+            }));
+        }
+
+        if let Some(buffer) = &self.data_buffer {
+            for range in self.dirty_data.drain(..) {
+                render_queue.write_buffer(
+                    buffer,
+                    range.start as u64 * 4,
+                    cast_slice(&self.data[range]),
+                );
+            }
+        }
+        if let Some(buffer) = &self.palette_buffer {
+            for range in self.dirty_palettes.drain(..) {
+                render_queue.write_buffer(
+                    buffer,
+                    range.start as u64 * 4,
+                    cast_slice(&self.palettes[range]),
+                );
+            }
+        }
+    }
+
+    /// Moves every slot next to each other, dropping the unused words
+    fn compact(&mut self) {
+        let mut data = Vec::with_capacity(self.data.len() - self.unused);
+        let mut palettes = Vec::with_capacity(self.palettes.len() - self.unused_palettes);
+        for slot in self.slots.values_mut() {
+            let start = data.len();
+            data.extend_from_slice(&self.data[slot.data.clone()]);
+            slot.data = start..data.len();
+
+            let start = palettes.len();
+            palettes.extend_from_slice(&self.palettes[slot.palette.clone()]);
+            slot.palette = start..palettes.len();
+        }
+
+        self.data = data;
+        self.palettes = palettes;
+        self.unused = 0;
+        self.unused_palettes = 0;
+        self.dirty_data = vec![0..self.data.len()];
+        self.dirty_palettes = vec![0..self.palettes.len()];
+    }
+}
+
+fn create_storage_buffer(render_device: &RenderDevice, label: &str, words: usize) -> Buffer {
+    render_device.create_buffer(&BufferDescriptor {
+        label: Some(label),
+        size: words as u64 * 4,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
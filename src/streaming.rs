@@ -0,0 +1,141 @@
+use bevy::prelude::*;
+
+use super::{
+    voxel::VoxelData,
+    world::{VoxelWorld, CHUNK_SIZE},
+};
+
+/// Streams the chunks of every [`VoxelWorld`] around this entity, usually the
+/// camera.
+///
+/// Missing chunks within `load_radius` are created empty and announced with
+/// [`ChunkLoaded`], chunks further than `unload_radius` from every loader are
+/// removed. Radii are in chunks, `unload_radius` should be the larger one so
+/// chunks at the edge do not reload every time the focus moves a little.
+#[derive(Component, Clone, Debug)]
+pub struct ChunkLoader {
+    pub load_radius: u32,
+    pub unload_radius: u32,
+}
+
+impl Default for ChunkLoader {
+    fn default() -> Self {
+        Self {
+            load_radius: 6,
+            unload_radius: 8,
+        }
+    }
+}
+
+/// Caps the work done by chunk streaming each frame
+#[derive(Clone, Debug)]
+pub struct ChunkStreamingBudget {
+    /// Chunks loaded per frame, nearest first
+    pub loads_per_frame: usize,
+    /// Chunks unloaded per frame, furthest first
+    pub unloads_per_frame: usize,
+}
+
+impl Default for ChunkStreamingBudget {
+    fn default() -> Self {
+        Self {
+            loads_per_frame: 8,
+            unloads_per_frame: 16,
+        }
+    }
+}
+
+/// Sent when a [`ChunkLoader`] created an empty chunk, which can then be
+/// filled through [`VoxelWorld::chunk`]
+#[derive(Clone, Debug)]
+pub struct ChunkLoaded {
+    pub world: Entity,
+    pub coords: IVec3,
+}
+
+/// Sent when a chunk left the range of every [`ChunkLoader`] and was removed
+#[derive(Clone, Debug)]
+pub struct ChunkUnloaded {
+    pub world: Entity,
+    pub coords: IVec3,
+}
+
+pub(crate) fn stream_chunks(
+    budget: Res<ChunkStreamingBudget>,
+    mut assets: ResMut<Assets<VoxelData>>,
+    mut loaded_events: EventWriter<ChunkLoaded>,
+    mut unloaded_events: EventWriter<ChunkUnloaded>,
+    loaders: Query<(&ChunkLoader, &GlobalTransform)>,
+    mut worlds: Query<(Entity, &mut VoxelWorld, &GlobalTransform)>,
+) {
+    if loaders.is_empty() {
+        return;
+    }
+
+    for (world_entity, mut world, world_transform) in worlds.iter_mut() {
+        let to_world = world_transform.compute_matrix().inverse();
+        let centers: Vec<_> = loaders
+            .iter()
+            .map(|(loader, transform)| {
+                let position = to_world.transform_point3(transform.translation());
+                let center = (position / CHUNK_SIZE as f32).floor().as_ivec3();
+                (loader, center)
+            })
+            .collect();
+        // Squared distance to the closest loader, relative to its radius
+        let distance = |coords: IVec3, radius: fn(&ChunkLoader) -> u32| {
+            centers
+                .iter()
+                .map(|(loader, center)| {
+                    let radius = radius(loader) as i32;
+                    (coords - *center).length_squared() - radius * radius
+                })
+                .min()
+                .unwrap()
+        };
+
+        let mut unloads: Vec<_> = world
+            .chunks()
+            .map(|(coords, _)| (distance(coords, |loader| loader.unload_radius), coords))
+            .filter(|(distance, _)| *distance > 0)
+            .collect();
+        unloads.sort_unstable_by_key(|(distance, _)| std::cmp::Reverse(*distance));
+        for (_, coords) in unloads.into_iter().take(budget.unloads_per_frame) {
+            world.remove_chunk(coords);
+            unloaded_events.send(ChunkUnloaded {
+                world: world_entity,
+                coords,
+            });
+        }
+
+        let mut loads = Vec::new();
+        for (loader, center) in &centers {
+            let radius = loader.load_radius as i32;
+            for z in -radius..=radius {
+                for y in -radius..=radius {
+                    for x in -radius..=radius {
+                        let offset = IVec3::new(x, y, z);
+                        let coords = *center + offset;
+                        if offset.length_squared() <= radius * radius
+                            && world.chunk(coords).is_none()
+                        {
+                            loads.push((offset.length_squared(), coords));
+                        }
+                    }
+                }
+            }
+        }
+        loads.sort_unstable_by_key(|(distance, _)| *distance);
+        for (_, coords) in loads.into_iter().take(budget.loads_per_frame) {
+            // Overlapping loaders can list a chunk more than once
+            if world.chunk(coords).is_some() {
+                continue;
+            }
+            world.create_chunk(&mut assets, coords);
+            loaded_events.send(ChunkLoaded {
+                world: world_entity,
+                coords,
+            });
+        }
+    }
+}
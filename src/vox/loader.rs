@@ -0,0 +1,469 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    utils::{BoxedFuture, HashMap},
+};
+use thiserror::Error;
+
+use super::{default_palette, scene::VoxSceneNode, vox_to_bevy, VoxScene, VOXELS_PER_UNIT};
+use crate::{VoxelCells, VoxelData, VoxelDataError};
+
+/// Loads MagicaVoxel `.vox` files.
+///
+/// The default asset is a [`VoxelData`] of the first model of the file.
+/// Every model is also available as a `Model<N>` labeled asset and the scene
+/// graph as a `Scene` labeled [`VoxScene`], e.g. `"castle.vox#Model2"` or
+/// `"castle.vox#Scene"`.
+#[derive(Default)]
+pub struct VoxLoader;
+
+#[derive(Error, Debug)]
+pub enum VoxError {
+    #[error("not a .vox file")]
+    InvalidHeader,
+    #[error("unexpected end of file")]
+    UnexpectedEof,
+    #[error("chunk {0} is malformed")]
+    MalformedChunk(String),
+    #[error("file contains no models")]
+    NoModels,
+    #[error("scene node {0} is missing")]
+    MissingNode(i32),
+    #[error("size {0} can't be stored in a .vox model, which is limited to 1..=256 per axis")]
+    UnsupportedSize(UVec3),
+    #[error(transparent)]
+    Data(#[from] VoxelDataError),
+}
+
+impl AssetLoader for VoxLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let vox = parse(bytes)?;
+
+            let models = vox
+                .models
+                .iter()
+                .enumerate()
+                .map(|(i, model)| {
+                    load_context.set_labeled_asset(
+                        &format!("Model{}", i),
+                        LoadedAsset::new(model.to_voxel_data(&vox.palette)),
+                    )
+                })
+                .collect();
+            let roots = vox.scene_roots()?;
+            load_context.set_labeled_asset("Scene", LoadedAsset::new(VoxScene { models, roots }));
+
+            let data = vox.models[0].to_voxel_data(&vox.palette);
+            load_context.set_default_asset(LoadedAsset::new(data));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox"]
+    }
+}
+
+pub(crate) struct VoxModel {
+    /// Size in `.vox` coordinates, within `1..=256` on every axis
+    pub(crate) size: IVec3,
+    /// `x`, `y`, `z` and palette index of every filled cell, inside `size`
+    pub(crate) voxels: Vec<[u8; 4]>,
+}
+
+enum VoxNode {
+    Transform {
+        name: Option<String>,
+        hidden: bool,
+        child: i32,
+        rotation: u8,
+        translation: IVec3,
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        model: Option<usize>,
+    },
+}
+
+pub(crate) struct VoxFile {
+    pub(crate) models: Vec<VoxModel>,
+    pub(crate) palette: [u32; 256],
+    nodes: HashMap<i32, VoxNode>,
+}
+
+impl VoxModel {
+    pub(crate) fn to_voxel_data(&self, palette: &[u32; 256]) -> VoxelData {
+        let size = UVec3::new(self.size.x as u32, self.size.z as u32, self.size.y as u32);
+        let mut indices = vec![0u8; (size.x * size.y * size.z) as usize];
+        for &[x, y, z, index] in &self.voxels {
+            let position = UVec3::new(x as u32, z as u32, y as u32);
+            if position.cmpge(size).any() {
+                continue;
+            }
+            let flipped_y = size.z - 1 - position.z;
+            let cell = position.x + flipped_y * size.x + position.y * size.x * size.z;
+            indices[cell as usize] = index;
+        }
+        VoxelData::from_cells(
+            size,
+            VoxelCells::Indexed8 {
+                indices,
+                palette: palette.to_vec(),
+            },
+        )
+    }
+}
+
+impl VoxFile {
+    fn scene_roots(&self) -> Result<Vec<VoxSceneNode>, VoxError> {
+        if self.nodes.contains_key(&0) {
+            return Ok(vec![self.scene_node(0, 0)?]);
+        }
+
+        // Files without a scene graph just place every model at the origin
+        Ok((0..self.models.len())
+            .map(|model| VoxSceneNode {
+                transform: self.node_transform(0, IVec3::ZERO, Some(model)),
+                model: Some(model),
+                ..default()
+            })
+            .collect())
+    }
+
+    fn scene_node(&self, id: i32, depth: usize) -> Result<VoxSceneNode, VoxError> {
+        // Guards against cyclic graphs in corrupted files
+        if depth > 64 {
+            return Err(VoxError::MalformedChunk("nTRN".to_string()));
+        }
+
+        match self.nodes.get(&id).ok_or(VoxError::MissingNode(id))? {
+            VoxNode::Transform {
+                name,
+                hidden,
+                child,
+                rotation,
+                translation,
+            } => {
+                let mut node = match self.nodes.get(child).ok_or(VoxError::MissingNode(*child))? {
+                    VoxNode::Shape { model } => VoxSceneNode {
+                        model: *model,
+                        ..default()
+                    },
+                    _ => self.scene_node(*child, depth + 1)?,
+                };
+                node.name = name.clone();
+                node.visible = !hidden;
+                node.transform = self.node_transform(*rotation, *translation, node.model);
+                Ok(node)
+            }
+            VoxNode::Group { children } => Ok(VoxSceneNode {
+                children: children
+                    .iter()
+                    .map(|&child| self.scene_node(child, depth + 1))
+                    .collect::<Result<_, _>>()?,
+                ..default()
+            }),
+            VoxNode::Shape { model } => Ok(VoxSceneNode {
+                transform: self.node_transform(0, IVec3::ZERO, *model),
+                model: *model,
+                ..default()
+            }),
+        }
+    }
+
+    /// Converts a `.vox` frame into a bevy transform, model nodes are also
+    /// scaled to [`VOXELS_PER_UNIT`] and offset to match MagicaVoxel's pivot.
+    fn node_transform(&self, rotation: u8, translation: IVec3, model: Option<usize>) -> Transform {
+        let rotation = rotation_matrix(rotation);
+        let mut translation = translation.as_vec3();
+        let mut scale = 1.0;
+
+        if let Some(model) = model.and_then(|model| self.models.get(model)) {
+            // MagicaVoxel pivots models around their floored center cell
+            let size = model.size.as_vec3();
+            translation += rotation * (size / 2.0 - (size / 2.0).floor());
+            scale = size.max_element() / VOXELS_PER_UNIT;
+        }
+
+        let basis = vox_to_bevy();
+        let mut transform =
+            Transform::from_matrix(Mat4::from_mat3(basis * rotation * basis.transpose()));
+        transform.translation = basis * translation / VOXELS_PER_UNIT;
+        transform.scale *= scale;
+        transform
+    }
+}
+
+/// Decodes MagicaVoxel's packed rotation byte, which describes the matrix row by row
+fn rotation_matrix(rotation: u8) -> Mat3 {
+    let first = ((rotation & 0b11) as usize).min(2);
+    let second = (((rotation >> 2) & 0b11) as usize).min(2);
+    let third = (0..3).find(|&i| i != first && i != second).unwrap_or(2);
+
+    let mut rows = [Vec3::ZERO; 3];
+    for (row, (index, sign_bit)) in [(first, 4), (second, 5), (third, 6)]
+        .into_iter()
+        .enumerate()
+    {
+        rows[row][index] = if rotation & (1 << sign_bit) != 0 {
+            -1.0
+        } else {
+            1.0
+        };
+    }
+    Mat3::from_cols(rows[0], rows[1], rows[2]).transpose()
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+        if len > self.bytes.len() {
+            return Err(VoxError::UnexpectedEof);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, VoxError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn len(&mut self) -> Result<usize, VoxError> {
+        usize::try_from(self.i32()?).map_err(|_| VoxError::UnexpectedEof)
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let len = self.len()?;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let len = self.len()?;
+        let mut dict = HashMap::default();
+        for _ in 0..len {
+            let key = self.string()?;
+            dict.insert(key, self.string()?);
+        }
+        Ok(dict)
+    }
+}
+
+pub(crate) fn parse(bytes: &[u8]) -> Result<VoxFile, VoxError> {
+    let mut reader = Reader { bytes };
+    if reader.take(4).map_err(|_| VoxError::InvalidHeader)? != b"VOX " {
+        return Err(VoxError::InvalidHeader);
+    }
+    let _version = reader.i32()?;
+
+    if reader.take(4)? != b"MAIN" {
+        return Err(VoxError::MalformedChunk("MAIN".to_string()));
+    }
+    let content_len = reader.len()?;
+    let children_len = reader.len()?;
+    reader.take(content_len)?;
+    let mut chunks = Reader {
+        bytes: reader.take(children_len)?,
+    };
+
+    let mut file = VoxFile {
+        models: Vec::new(),
+        palette: default_palette(),
+        nodes: HashMap::default(),
+    };
+    let mut size = None;
+
+    while !chunks.bytes.is_empty() {
+        let id = chunks.take(4)?;
+        let content_len = chunks.len()?;
+        let children_len = chunks.len()?;
+        let mut content = Reader {
+            bytes: chunks.take(content_len)?,
+        };
+        chunks.take(children_len)?;
+
+        let malformed = || VoxError::MalformedChunk(String::from_utf8_lossy(id).into_owned());
+        match id {
+            b"SIZE" => {
+                let model_size = IVec3::new(content.i32()?, content.i32()?, content.i32()?);
+                if model_size.cmplt(IVec3::ZERO).any() {
+                    return Err(malformed());
+                }
+                if model_size.cmpeq(IVec3::ZERO).any() || model_size.cmpgt(IVec3::splat(256)).any()
+                {
+                    return Err(VoxError::UnsupportedSize(model_size.as_uvec3()));
+                }
+                size = Some(model_size);
+            }
+            b"XYZI" => {
+                // Every XYZI chunk is preceded by the SIZE chunk of its model
+                let size = size.take().ok_or_else(malformed)?;
+                let len = content.len()?;
+                let mut voxels = Vec::with_capacity(len.min(content.bytes.len() / 4));
+                for _ in 0..len {
+                    let voxel = [content.u8()?, content.u8()?, content.u8()?, content.u8()?];
+                    let position = IVec3::new(voxel[0] as i32, voxel[1] as i32, voxel[2] as i32);
+                    if position.cmpge(size).any() {
+                        return Err(malformed());
+                    }
+                    voxels.push(voxel);
+                }
+                file.models.push(VoxModel { size, voxels });
+            }
+            b"RGBA" => {
+                // Palette index `i` is stored at position `i - 1`, index 0 stays empty
+                for entry in file.palette[1..].iter_mut() {
+                    let [r, g, b, a] = [content.u8()?, content.u8()?, content.u8()?, content.u8()?];
+                    *entry = u32::from_be_bytes([a, r, g, b]);
+                }
+            }
+            b"nTRN" => {
+                let node = content.i32()?;
+                let attributes = content.dict()?;
+                let child = content.i32()?;
+                let _reserved = content.i32()?;
+                let _layer = content.i32()?;
+                let frames = content.len()?;
+                let frame = if frames > 0 {
+                    content.dict()?
+                } else {
+                    HashMap::default()
+                };
+
+                let rotation = frame
+                    .get("_r")
+                    .map(|r| r.parse::<u8>().map_err(|_| malformed()))
+                    .transpose()?
+                    .unwrap_or(0);
+                let translation = frame
+                    .get("_t")
+                    .map(|t| {
+                        let t = t
+                            .split_whitespace()
+                            .map(str::parse)
+                            .collect::<Result<Vec<i32>, _>>()
+                            .map_err(|_| malformed())?;
+                        match t[..] {
+                            [x, y, z] => Ok(IVec3::new(x, y, z)),
+                            _ => Err(malformed()),
+                        }
+                    })
+                    .transpose()?
+                    .unwrap_or(IVec3::ZERO);
+
+                file.nodes.insert(
+                    node,
+                    VoxNode::Transform {
+                        name: attributes.get("_name").cloned(),
+                        hidden: attributes.get("_hidden").map_or(false, |h| h == "1"),
+                        child,
+                        rotation,
+                        translation,
+                    },
+                );
+            }
+            b"nGRP" => {
+                let node = content.i32()?;
+                let _attributes = content.dict()?;
+                let len = content.len()?;
+                let children = (0..len).map(|_| content.i32()).collect::<Result<_, _>>()?;
+                file.nodes.insert(node, VoxNode::Group { children });
+            }
+            b"nSHP" => {
+                let node = content.i32()?;
+                let _attributes = content.dict()?;
+                let len = content.len()?;
+                let model = if len > 0 {
+                    usize::try_from(content.i32()?).ok()
+                } else {
+                    None
+                };
+                file.nodes.insert(node, VoxNode::Shape { model });
+            }
+            // PACK, MATL, LAYR, rOBJ, rCAM, NOTE, IMAP and unknown chunks
+            _ => {}
+        }
+    }
+
+    if file.models.is_empty() {
+        return Err(VoxError::NoModels);
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `.vox` file with a single model of `size` holding `voxels`
+    fn vox_file(size: [i32; 3], voxels: &[[u8; 4]]) -> Vec<u8> {
+        let chunk = |id: &[u8; 4], content: Vec<u8>| {
+            let mut chunk = id.to_vec();
+            chunk.extend((content.len() as i32).to_le_bytes());
+            chunk.extend(0i32.to_le_bytes());
+            chunk.extend(content);
+            chunk
+        };
+        let mut xyzi = (voxels.len() as i32).to_le_bytes().to_vec();
+        xyzi.extend(voxels.iter().flatten());
+        let mut children = chunk(
+            b"SIZE",
+            size.iter().flat_map(|axis| axis.to_le_bytes()).collect(),
+        );
+        children.extend(chunk(b"XYZI", xyzi));
+
+        let mut bytes = b"VOX ".to_vec();
+        bytes.extend(150i32.to_le_bytes());
+        bytes.extend(b"MAIN");
+        bytes.extend(0i32.to_le_bytes());
+        bytes.extend((children.len() as i32).to_le_bytes());
+        bytes.extend(children);
+        bytes
+    }
+
+    #[test]
+    fn parses_model() {
+        let file = parse(&vox_file([2, 3, 4], &[[1, 2, 3, 7]])).unwrap();
+        let data = file.models[0].to_voxel_data(&file.palette);
+        assert_eq!(data.size(), UVec3::new(2, 4, 3));
+        assert_eq!(data.get(UVec3::new(1, 3, 0)), Ok(file.palette[7]));
+        assert_eq!(data.iter().count(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in [[0, 4, 4], [4, 257, 4], [4, 4, i32::MAX]] {
+            assert!(matches!(
+                parse(&vox_file(size, &[])),
+                Err(VoxError::UnsupportedSize(_))
+            ));
+        }
+        assert!(matches!(
+            parse(&vox_file([-1, 4, 4], &[])),
+            Err(VoxError::MalformedChunk(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_voxels_outside_the_model() {
+        assert!(matches!(
+            parse(&vox_file([4, 4, 4], &[[1, 4, 1, 1]])),
+            Err(VoxError::MalformedChunk(_))
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+//! Support for reading and writing [MagicaVoxel](https://ephtracy.github.io/) `.vox` files.
+//!
+//! MagicaVoxel is Z-up while bevy is Y-up, so a `.vox` cell `(x, y, z)` of a
+//! model of size `(w, d, h)` ends up at `(x, z, d - 1 - y)` of a [`VoxelData`](crate::VoxelData)
+//! of size `(w, h, d)`.
+
+use bevy::prelude::*;
+
+mod loader;
+mod scene;
+mod writer;
+
+pub use loader::{VoxError, VoxLoader};
+pub use scene::{VoxScene, VoxSceneNode, VoxSceneSpawned};
+pub use writer::write_vox;
+
+pub(crate) use scene::spawn_vox_scenes;
+
+/// Number of `.vox` voxels per world unit in spawned [`VoxScene`]s,
+/// a 16³ model spans exactly one unit like [`VoxelData::default`](crate::VoxelData::default)
+pub const VOXELS_PER_UNIT: f32 = 16.0;
+
+/// Palette used by `.vox` files without an `RGBA` chunk, in ARGB.
+///
+/// Entry 0 is transparent, followed by a 6×6×6 color cube without black and
+/// ten step ramps of blue, green, red and grey.
+pub fn default_palette() -> [u32; 256] {
+    const CUBE: [u32; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u32; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [0u32; 256];
+    let cube = CUBE.iter().flat_map(|&r| {
+        CUBE.iter()
+            .flat_map(move |&g| CUBE.iter().map(move |&b| argb(r, g, b)))
+    });
+    let ramps = RAMP
+        .iter()
+        .map(|&b| argb(0, 0, b))
+        .chain(RAMP.iter().map(|&g| argb(0, g, 0)))
+        .chain(RAMP.iter().map(|&r| argb(r, 0, 0)))
+        .chain(RAMP.iter().map(|&v| argb(v, v, v)));
+    for (entry, color) in palette[1..].iter_mut().zip(cube.take(215).chain(ramps)) {
+        *entry = color;
+    }
+    palette
+}
+
+fn argb(r: u32, g: u32, b: u32) -> u32 {
+    0xff000000 | r << 16 | g << 8 | b
+}
+
+/// Maps a `.vox` direction into bevy's coordinate system
+pub(crate) fn vox_to_bevy() -> Mat3 {
+    Mat3::from_cols(Vec3::X, -Vec3::Z, Vec3::Y)
+}
@@ -0,0 +1,93 @@
+use bevy::{prelude::*, reflect::TypeUuid};
+
+use crate::{Voxel, VoxelBundle, VoxelData};
+
+/// Models and scene graph of a `.vox` file.
+///
+/// Insert a `Handle<VoxScene>` on an entity with a [`SpatialBundle`] and the
+/// scene hierarchy is spawned as its children once the asset is loaded.
+#[derive(TypeUuid)]
+#[uuid = "3a1f6d0e-5c2b-4f8e-9b61-2d7c4e0a9f13"]
+pub struct VoxScene {
+    pub models: Vec<Handle<VoxelData>>,
+    pub roots: Vec<VoxSceneNode>,
+}
+
+#[derive(Clone)]
+pub struct VoxSceneNode {
+    pub name: Option<String>,
+    pub visible: bool,
+    /// Transform relative to the parent node
+    pub transform: Transform,
+    /// Index into [`VoxScene::models`] of the model drawn by this node
+    pub model: Option<usize>,
+    pub children: Vec<VoxSceneNode>,
+}
+
+/// Marks entities whose [`VoxScene`] has already been spawned
+#[derive(Component)]
+pub struct VoxSceneSpawned;
+
+impl Default for VoxSceneNode {
+    fn default() -> Self {
+        Self {
+            name: None,
+            visible: true,
+            transform: Transform::identity(),
+            model: None,
+            children: Vec::new(),
+        }
+    }
+}
+
+impl VoxScene {
+    /// Spawns the scene hierarchy as children of `parent`
+    pub fn spawn_children(&self, parent: &mut ChildBuilder) {
+        for node in &self.roots {
+            self.spawn_node(parent, node);
+        }
+    }
+
+    fn spawn_node(&self, parent: &mut ChildBuilder, node: &VoxSceneNode) {
+        let visibility = Visibility {
+            is_visible: node.visible,
+        };
+        let mut entity = match node.model.and_then(|model| self.models.get(model)) {
+            Some(data) => parent.spawn_bundle(VoxelBundle {
+                voxel: Voxel { data: data.clone() },
+                transform: node.transform,
+                visibility,
+                ..default()
+            }),
+            None => parent.spawn_bundle(SpatialBundle {
+                transform: node.transform,
+                visibility,
+                ..default()
+            }),
+        };
+
+        if let Some(name) = &node.name {
+            entity.insert(Name::new(name.clone()));
+        }
+        entity.with_children(|parent| {
+            for child in &node.children {
+                self.spawn_node(parent, child);
+            }
+        });
+    }
+}
+
+pub(crate) fn spawn_vox_scenes(
+    mut commands: Commands,
+    scenes: Res<Assets<VoxScene>>,
+    query: Query<(Entity, &Handle<VoxScene>), Without<VoxSceneSpawned>>,
+) {
+    for (entity, handle) in query.iter() {
+        if let Some(scene) = scenes.get(handle) {
+            commands
+                .entity(entity)
+                .insert(VoxSceneSpawned)
+                .with_children(|parent| scene.spawn_children(parent));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::VoxError;
+use crate::{VoxelCells, VoxelData, VoxelDataError};
+
+/// Largest grid dimension a `.vox` model can have
+const MAX_SIZE: u32 = 256;
+
+/// Number of usable palette entries, index 0 means an empty cell
+const PALETTE_SIZE: usize = 255;
+
+/// Serializes `data` into a MagicaVoxel `.vox` file with a single model.
+///
+/// Cells with zero alpha are left empty. If the model uses more than 255
+/// distinct colors, the 255 most frequent ones form the palette and every
+/// other color is replaced with its nearest palette entry.
+///
+/// Grids of voxel types have no colors to write and are rejected.
+pub fn write_vox(data: &VoxelData) -> Result<Vec<u8>, VoxError> {
+    let size = data.size();
+    if size.max_element() > MAX_SIZE || size.min_element() == 0 {
+        return Err(VoxError::UnsupportedSize(size));
+    }
+    if matches!(data.cells(), VoxelCells::Typed(_)) {
+        return Err(VoxelDataError::FormatMismatch.into());
+    }
+
+    // See the module docs for the axis conversion
+    let voxels: Vec<_> = data
+        .iter()
+        .map(|(position, color)| {
+            let [x, y, z] = [position.x, size.z - 1 - position.z, position.y];
+            ([x as u8, y as u8, z as u8], color)
+        })
+        .collect();
+
+    let palette = build_palette(voxels.iter().map(|&(_, color)| color));
+    let mut lookup = HashMap::default();
+
+    let mut xyzi = Vec::with_capacity(4 + voxels.len() * 4);
+    xyzi.extend_from_slice(&(voxels.len() as i32).to_le_bytes());
+    for ([x, y, z], color) in voxels {
+        let index = *lookup
+            .entry(color)
+            .or_insert_with(|| nearest_color(&palette, color));
+        xyzi.extend_from_slice(&[x, y, z, index]);
+    }
+
+    let mut size_chunk = Vec::with_capacity(12);
+    for axis in [size.x, size.z, size.y] {
+        size_chunk.extend_from_slice(&(axis as i32).to_le_bytes());
+    }
+
+    // Entry `i` of the chunk holds palette index `i + 1`
+    let mut rgba = Vec::with_capacity(256 * 4);
+    for i in 0..256 {
+        let [a, r, g, b] = palette.get(i).copied().unwrap_or(0).to_be_bytes();
+        rgba.extend_from_slice(&[r, g, b, a]);
+    }
+
+    let mut children = Vec::new();
+    write_chunk(&mut children, b"SIZE", &size_chunk, &[]);
+    write_chunk(&mut children, b"XYZI", &xyzi, &[]);
+    write_chunk(&mut children, b"RGBA", &rgba, &[]);
+
+    let mut bytes = Vec::with_capacity(20 + children.len());
+    bytes.extend_from_slice(b"VOX ");
+    bytes.extend_from_slice(&150i32.to_le_bytes());
+    write_chunk(&mut bytes, b"MAIN", &[], &children);
+    Ok(bytes)
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(content.len() as i32).to_le_bytes());
+    out.extend_from_slice(&(children.len() as i32).to_le_bytes());
+    out.extend_from_slice(content);
+    out.extend_from_slice(children);
+}
+
+/// Picks up to 255 colors, most frequent first
+fn build_palette(colors: impl Iterator<Item = u32>) -> Vec<u32> {
+    let mut counts = HashMap::<u32, usize>::default();
+    for color in colors {
+        *counts.entry(color).or_default() += 1;
+    }
+
+    let mut palette: Vec<_> = counts.into_iter().collect();
+    // Ties are broken by color so the output is deterministic
+    palette.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    palette.truncate(PALETTE_SIZE);
+    palette.into_iter().map(|(color, _)| color).collect()
+}
+
+/// `.vox` index of the palette entry closest to `color` in RGBA space
+fn nearest_color(palette: &[u32], color: u32) -> u8 {
+    let distance = |other: u32| {
+        color
+            .to_be_bytes()
+            .iter()
+            .zip(other.to_be_bytes())
+            .map(|(&a, b)| (a as i32 - b as i32).pow(2))
+            .sum::<i32>()
+    };
+
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|&(_, &entry)| distance(entry))
+        .map_or(0, |(i, _)| i as u8 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::loader::parse, *};
+
+    fn round_trip(data: &VoxelData) -> (VoxelData, [u32; 256]) {
+        let file = parse(&write_vox(data).unwrap()).unwrap();
+        (file.models[0].to_voxel_data(&file.palette), file.palette)
+    }
+
+    #[test]
+    fn round_trips_cells_and_palette() {
+        let size = UVec3::new(5, 3, 7);
+        let mut data = VoxelData::new(size);
+        let positions: Vec<_> = (0..(size.x * size.y * size.z) as usize)
+            .map(|index| data.position(index))
+            .collect();
+        let colors = [0xffff0000, 0xff00ff00, 0x800000ff];
+        for (i, &position) in positions.iter().enumerate().filter(|(i, _)| i % 4 != 0) {
+            data.set(position, colors[i % colors.len()]).unwrap();
+        }
+
+        let (loaded, palette) = round_trip(&data);
+        assert_eq!(loaded.size(), size);
+        for &position in &positions {
+            assert_eq!(loaded.get(position), data.get(position));
+        }
+        let mut used: Vec<_> = palette[1..].iter().copied().filter(|&c| c != 0).collect();
+        used.sort_unstable();
+        let mut expected = colors.to_vec();
+        expected.sort_unstable();
+        assert_eq!(used, expected);
+    }
+
+    #[test]
+    fn quantizes_more_than_255_colors() {
+        let size = UVec3::new(16, 4, 16);
+        let mut data = VoxelData::new(size);
+        // The first 255 colors are used twice and make the palette
+        let color = |i: u32| 0xff000000 | (i * 0x010307) & 0xffffff;
+        let cells =
+            (0..300).flat_map(|i| std::iter::repeat(color(i)).take(if i < 255 { 2 } else { 1 }));
+        for (index, color) in cells.enumerate() {
+            data.set(data.position(index), color).unwrap();
+        }
+
+        let (loaded, palette) = round_trip(&data);
+        for (position, color) in data.iter() {
+            let loaded = loaded.get(position).unwrap();
+            if palette[1..].contains(&color) {
+                assert_eq!(loaded, color);
+            } else {
+                assert_eq!(
+                    loaded,
+                    palette[nearest_color(&palette[1..], color) as usize]
+                );
+            }
+        }
+        assert_eq!(loaded.iter().count(), data.iter().count());
+        assert!((0..255).all(|i| palette[1..].contains(&color(i))));
+    }
+
+    #[test]
+    fn rejects_typed_grids() {
+        let data = VoxelData::with_types(UVec3::splat(2));
+        assert!(matches!(
+            write_vox(&data),
+            Err(VoxError::Data(VoxelDataError::FormatMismatch))
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_grids() {
+        let data = VoxelData::new(UVec3::new(257, 1, 1));
+        assert!(matches!(
+            write_vox(&data),
+            Err(VoxError::UnsupportedSize(_))
+        ));
+    }
+}
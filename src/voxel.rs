@@ -1,71 +1,740 @@
-use bevy::{
-    core::cast_slice,
-    ecs::system::{lifetimeless::SRes, SystemParamItem},
-    prelude::*,
-    reflect::TypeUuid,
-    render::{
-        extract_component::ExtractComponent,
-        render_asset::{PrepareAssetError, RenderAsset},
-        render_resource::{
-            BindGroup, BindGroupDescriptor, BindGroupEntry, Buffer, BufferInitDescriptor,
-            BufferUsages,
-        },
-        renderer::RenderDevice,
-    },
-};
+use std::{ops::Range, sync::Mutex};
+
+use bevy::{prelude::*, reflect::TypeUuid, render::extract_component::ExtractComponent};
+
+use thiserror::Error;
 
-use super::pipeline;
+use super::voxel_type::{VoxelTypeId, VoxelTypeRegistry};
 
-#[repr(transparent)]
-#[derive(Clone, TypeUuid)]
+/// Dense grid of voxels.
+///
+/// Cells are laid out as `x + z * width + y * width * depth`. Edits made
+/// through [`VoxelData::set`] and friends are tracked, so only the changed
+/// parts are uploaded to the GPU.
+#[derive(TypeUuid)]
 #[uuid = "180e10f3-5c78-43ed-8b46-69af97071fdc"]
-pub struct VoxelData(pub [u32; 16 * 16 * 16]);
+pub struct VoxelData {
+    pub(crate) size: UVec3,
+    pub(crate) cells: VoxelCells,
+    /// Locked so the render world can take it while extracting
+    dirty: Mutex<DirtyRegion>,
+    /// Result of [`VoxelData::bounds`], kept until an edit may shrink it
+    bounds: Mutex<Option<Option<(UVec3, UVec3)>>>,
+    /// Kept up to date by edits, rebuilt on next use after palette edits
+    summary: Mutex<Option<CellSummary>>,
+}
 
+/// Storage of the cells of a [`VoxelData`]
 #[derive(Clone)]
-pub struct VoxelMeta {
-    pub(crate) _buffer: Buffer,
-    pub(crate) bind_group: BindGroup,
+pub enum VoxelCells {
+    /// Every cell stores its own ARGB color
+    Color(Vec<u32>),
+    /// Cells store indices into an ARGB palette of up to 256 entries
+    Indexed8 { indices: Vec<u8>, palette: Vec<u32> },
+    /// Cells store indices into an ARGB palette of up to 65536 entries
+    Indexed16 {
+        indices: Vec<u16>,
+        palette: Vec<u32>,
+    },
+    /// Cells store ids of types in the
+    /// [`VoxelTypeRegistry`](crate::VoxelTypeRegistry), which defines their look
+    Typed(Vec<u16>),
 }
 
-impl Default for VoxelData {
-    fn default() -> Self {
-        Self([0u32; 16 * 16 * 16])
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VoxelDataError {
+    #[error("position {position} is outside of the {size} grid")]
+    OutOfBounds { position: UVec3, size: UVec3 },
+    #[error("palette has no room for color {0:#010x}")]
+    PaletteFull(u32),
+    #[error("grid stores voxel types and colors can't be set on it, or the other way around")]
+    FormatMismatch,
+    #[error("grid of size {0} is too large for a sparse voxel tree")]
+    TooLarge(UVec3),
+}
+
+/// Occupancy and translucency of the cells of a [`VoxelData`], so uploads
+/// don't scan the whole grid after every edit
+#[derive(Clone)]
+struct CellSummary {
+    /// See [`VoxelData::occupancy`]
+    occupancy: Vec<u32>,
+    /// Cells with partial alpha, in grids of colors
+    translucent_cells: usize,
+    /// Cells of each type id, in typed grids
+    type_counts: Vec<u32>,
+}
+
+/// Parts of a [`VoxelData`] edited since its last upload to the GPU
+#[derive(Default)]
+pub(crate) struct DirtyRegion {
+    /// Edited cells in memory layout order
+    pub(crate) cells: Vec<Range<usize>>,
+    /// Changed words of the occupancy pyramid
+    pub(crate) occupancy: Vec<Range<usize>>,
+    pub(crate) palette: bool,
+    /// Buffer sizes changed, so everything has to be uploaded again
+    pub(crate) rebuild: bool,
+}
+
+/// Past this many ranges dirty cells or words are merged into a single range
+const MAX_DIRTY_RANGES: usize = 64;
+
+/// Largest grid edge, occupied bounds are packed into 16 bits per axis for
+/// the GPU
+const MAX_SIZE: u32 = u16::MAX as u32;
+
+/// Edge in cells of the bricks of each occupancy level, matching the levels
+/// skipped by `voxel.wgsl`
+const OCCUPANCY_BRICKS: [u32; 2] = [2, 4];
+
+impl VoxelData {
+    /// Creates an empty grid of the given dimensions
+    ///
+    /// # Panics
+    ///
+    /// Panics if a dimension is over 65535 or the grid has 2³² cells or more,
+    /// the other constructors check the same
+    pub fn new(size: UVec3) -> Self {
+        Self::from_cells(
+            size,
+            VoxelCells::Color(vec![0u32; Self::checked_volume(size)]),
+        )
+    }
+
+    /// Creates a grid of the given dimensions from already laid out cells
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` length does not match `size`
+    pub fn from_data(size: UVec3, data: Vec<u32>) -> Self {
+        assert_eq!(
+            data.len(),
+            Self::checked_volume(size),
+            "voxel data length does not match grid size"
+        );
+        Self::from_cells(size, VoxelCells::Color(data))
+    }
+
+    /// Creates an empty palette-indexed grid of the given dimensions
+    ///
+    /// Cell indices are stored as `u8` if `palette` has up to 256 entries and
+    /// as `u16` otherwise. Index 0 is what empty cells point to, so it should
+    /// normally be a transparent color.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `palette` has more than 65536 entries
+    pub fn with_palette(size: UVec3, palette: Vec<u32>) -> Self {
+        let volume = Self::checked_volume(size);
+        let cells = match palette.len() {
+            0..=256 => VoxelCells::Indexed8 {
+                indices: vec![0u8; volume],
+                palette,
+            },
+            257..=65536 => VoxelCells::Indexed16 {
+                indices: vec![0u16; volume],
+                palette,
+            },
+            _ => panic!("voxel palette can have at most 65536 entries"),
+        };
+        Self::from_cells(size, cells)
+    }
+
+    /// Creates a grid of the given dimensions filled with [`VoxelTypeId::AIR`],
+    /// whose cells are set with [`VoxelData::set_type`]
+    pub fn with_types(size: UVec3) -> Self {
+        Self::from_cells(
+            size,
+            VoxelCells::Typed(vec![0u16; Self::checked_volume(size)]),
+        )
+    }
+
+    pub(crate) fn from_cells(size: UVec3, cells: VoxelCells) -> Self {
+        assert_eq!(cells.len(), Self::checked_volume(size));
+        let summary = CellSummary::new(size, &cells);
+        Self {
+            size,
+            cells,
+            dirty: Default::default(),
+            bounds: Default::default(),
+            summary: Mutex::new(Some(summary)),
+        }
+    }
+
+    /// Grid dimensions: `x` is width, `y` is height and `z` is depth
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    /// Cell storage of the grid
+    pub fn cells(&self) -> &VoxelCells {
+        &self.cells
+    }
+
+    /// Palette of an indexed grid, `None` for per-cell colors
+    pub fn palette(&self) -> Option<&[u32]> {
+        match &self.cells {
+            VoxelCells::Color(_) | VoxelCells::Typed(_) => None,
+            VoxelCells::Indexed8 { palette, .. } | VoxelCells::Indexed16 { palette, .. } => {
+                Some(palette)
+            }
+        }
+    }
+
+    /// Mutable palette of an indexed grid, editing it recolors every cell
+    /// pointing to the changed entries
+    pub fn palette_mut(&mut self) -> Option<&mut [u32]> {
+        self.palette()?;
+        // Entries can turn transparent or opaque
+        self.invalidate_summary();
+        self.dirty.get_mut().unwrap().palette = true;
+        match &mut self.cells {
+            VoxelCells::Color(_) | VoxelCells::Typed(_) => None,
+            VoxelCells::Indexed8 { palette, .. } | VoxelCells::Indexed16 { palette, .. } => {
+                Some(palette)
+            }
+        }
+    }
+
+    /// Whether `position` is inside the grid
+    pub fn contains(&self, position: UVec3) -> bool {
+        position.cmplt(self.size).all()
+    }
+
+    /// Index of the cell at `position` in memory layout order
+    pub fn index(&self, position: UVec3) -> Result<usize, VoxelDataError> {
+        if !self.contains(position) {
+            return Err(VoxelDataError::OutOfBounds {
+                position,
+                size: self.size,
+            });
+        }
+        let (x, y, z) = (
+            position.x as usize,
+            position.y as usize,
+            position.z as usize,
+        );
+        let (width, depth) = (self.size.x as usize, self.size.z as usize);
+        Ok(x + z * width + y * width * depth)
+    }
+
+    /// Position of the cell at memory layout `index`
+    pub fn position(&self, index: usize) -> UVec3 {
+        let index = index as u32;
+        let layer = self.size.x * self.size.z;
+        UVec3::new(
+            index % self.size.x,
+            index / layer,
+            index % layer / self.size.x,
+        )
+    }
+
+    /// ARGB color of the cell at `position` in a grid of colors, typed grids
+    /// have no colors and are rejected
+    pub fn get(&self, position: UVec3) -> Result<u32, VoxelDataError> {
+        let index = self.index(position)?;
+        match &self.cells {
+            VoxelCells::Typed(_) => Err(VoxelDataError::FormatMismatch),
+            cells => Ok(cells.color(index)),
+        }
+    }
+
+    /// Sets the ARGB color of the cell at `position`.
+    ///
+    /// Indexed grids reuse a matching palette entry or append a new one,
+    /// an 8-bit grid is widened to 16-bit indices once 256 entries are in use.
+    pub fn set(&mut self, position: UVec3, color: u32) -> Result<(), VoxelDataError> {
+        let index = self.index(position)?;
+        let format = self.cells.format();
+        let palette_len = self.palette().map_or(0, <[u32]>::len);
+        let previous = self.cells.color(index);
+        self.cells.set(index, color)?;
+        if let Some(summary) = self.summary.get_mut().unwrap() {
+            summary.translucent_cells += usize::from(is_translucent_color(color));
+            summary.translucent_cells -= usize::from(is_translucent_color(previous));
+        }
+
+        let rebuild =
+            self.cells.format() != format || self.palette().map_or(0, <[u32]>::len) != palette_len;
+        let dirty = self.dirty.get_mut().unwrap();
+        if rebuild {
+            dirty.rebuild = true;
+        } else {
+            dirty.mark(index..index + 1);
+        }
+        self.update_occupancy(index);
+        self.update_bounds(index);
+        Ok(())
+    }
+
+    /// Type of the cell at `position` in a typed grid
+    pub fn get_type(&self, position: UVec3) -> Result<VoxelTypeId, VoxelDataError> {
+        let index = self.index(position)?;
+        match &self.cells {
+            VoxelCells::Typed(types) => Ok(VoxelTypeId(types[index])),
+            _ => Err(VoxelDataError::FormatMismatch),
+        }
+    }
+
+    /// Sets the type of the cell at `position` in a typed grid
+    pub fn set_type(&mut self, position: UVec3, id: VoxelTypeId) -> Result<(), VoxelDataError> {
+        let index = self.index(position)?;
+        let previous = match &mut self.cells {
+            VoxelCells::Typed(types) => std::mem::replace(&mut types[index], id.0),
+            _ => return Err(VoxelDataError::FormatMismatch),
+        };
+        if let Some(summary) = self.summary.get_mut().unwrap() {
+            summary.count_type(previous, -1);
+            summary.count_type(id.0, 1);
+        }
+        self.dirty.get_mut().unwrap().mark(index..index + 1);
+        self.update_occupancy(index);
+        self.update_bounds(index);
+        Ok(())
+    }
+
+    /// Iterates over positions and types of non-air cells in a typed grid,
+    /// yields nothing for other grids
+    pub fn iter_types(&self) -> impl Iterator<Item = (UVec3, VoxelTypeId)> + '_ {
+        let types = match &self.cells {
+            VoxelCells::Typed(types) => types.as_slice(),
+            _ => &[],
+        };
+        types
+            .iter()
+            .enumerate()
+            .filter(|(_, &id)| id != VoxelTypeId::AIR.0)
+            .map(|(index, &id)| (self.position(index), VoxelTypeId(id)))
+    }
+
+    /// Iterates over positions and ARGB colors of cells with non-zero alpha,
+    /// yields nothing for typed grids, see [`VoxelData::iter_types`]
+    pub fn iter(&self) -> impl Iterator<Item = (UVec3, u32)> + '_ {
+        (0..Self::volume(self.size)).filter_map(|index| {
+            let color = self.cells.color(index);
+            (color >> 24 != 0).then(|| (self.position(index), color))
+        })
+    }
+
+    /// Sets every cell in the `min..max` box to `color`
+    pub fn fill_box(&mut self, min: UVec3, max: UVec3, color: u32) -> Result<(), VoxelDataError> {
+        if max.cmpgt(self.size).any() {
+            return Err(VoxelDataError::OutOfBounds {
+                position: max,
+                size: self.size,
+            });
+        }
+        for y in min.y..max.y {
+            for z in min.z..max.z {
+                for x in min.x..max.x {
+                    self.set(UVec3::new(x, y, z), color)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Empties every cell, palettes are kept as is
+    pub fn clear(&mut self) {
+        match &mut self.cells {
+            VoxelCells::Color(colors) => colors.fill(0),
+            VoxelCells::Indexed8 { indices, .. } => indices.fill(0),
+            VoxelCells::Indexed16 { indices, .. } => indices.fill(0),
+            VoxelCells::Typed(types) => types.fill(0),
+        }
+        let volume = Self::volume(self.size);
+        self.dirty.get_mut().unwrap().mark(0..volume);
+        // Index 0 may point to an opaque palette entry
+        self.invalidate_summary();
+    }
+
+    /// Smallest box holding every occupied cell as `(min, max)` with `max`
+    /// exclusive, `None` if the grid is empty.
+    ///
+    /// The grid is only scanned again after clearing a cell on the border of
+    /// the box, clearing the grid or editing the palette, other edits update
+    /// the box in place.
+    pub fn bounds(&self) -> Option<(UVec3, UVec3)> {
+        *self.bounds.lock().unwrap().get_or_insert_with(|| {
+            (0..Self::volume(self.size))
+                .filter(|&index| self.cells.is_occupied(index))
+                .map(|index| self.position(index))
+                .fold(None, |bounds, position| match bounds {
+                    None => Some((position, position + 1)),
+                    Some((min, max)) => Some((min.min(position), max.max(position + 1))),
+                })
+        })
+    }
+
+    /// Keeps the cached bounds in line with an edit of the cell at `index`
+    fn update_bounds(&mut self, index: usize) {
+        let position = self.position(index);
+        let occupied = self.cells.is_occupied(index);
+        let cached = self.bounds.get_mut().unwrap();
+        match (*cached, occupied) {
+            (None, _) => {}
+            (Some(None), true) => *cached = Some(Some((position, position + 1))),
+            (Some(Some((min, max))), true) => {
+                *cached = Some(Some((min.min(position), max.max(position + 1))))
+            }
+            (Some(Some((min, max))), false) => {
+                if position.cmpeq(min).any() || (position + 1).cmpeq(max).any() {
+                    *cached = None;
+                }
+            }
+            (Some(None), false) => {}
+        }
+    }
+
+    /// Keeps the occupancy pyramid in line with an edit of the cell at
+    /// `index` and marks the words that changed
+    fn update_occupancy(&mut self, index: usize) {
+        let position = self.position(index);
+        let (size, cells) = (self.size, &self.cells);
+        let summary = match self.summary.get_mut().unwrap() {
+            Some(summary) => summary,
+            None => return,
+        };
+        let dirty = self.dirty.get_mut().unwrap();
+
+        let mut occupied = cells.is_occupied(index);
+        let mut start = 0;
+        for brick in OCCUPANCY_BRICKS {
+            let bricks = (size + brick - 1) / brick;
+            let at = position / brick;
+            // Emptying a cell leaves its brick occupied if other cells are
+            occupied = occupied || cells.any_occupied(size, at * brick, (at + 1) * brick);
+            let bit = (at.x + at.z * bricks.x + at.y * bricks.x * bricks.z) as usize;
+            let word = start + bit / 32;
+            let previous = summary.occupancy[word];
+            if occupied {
+                summary.occupancy[word] |= 1 << (bit % 32);
+            } else {
+                summary.occupancy[word] &= !(1 << (bit % 32));
+            }
+            // Coarser levels can't change if this one didn't
+            if summary.occupancy[word] == previous {
+                break;
+            }
+            dirty.mark_occupancy(word..word + 1);
+            start += (Self::volume(bricks) + 31) / 32;
+        }
+    }
+
+    /// Drops what edits keep up to date, for edits that can change any cell
+    fn invalidate_summary(&mut self) {
+        *self.summary.get_mut().unwrap() = None;
+        *self.bounds.get_mut().unwrap() = None;
+        let words = Self::occupancy_words(self.size);
+        self.dirty.get_mut().unwrap().mark_occupancy(0..words);
+    }
+
+    /// Runs `f` on the summary, rebuilding it first if it was dropped
+    fn with_summary<R>(&self, f: impl FnOnce(&CellSummary) -> R) -> R {
+        let mut summary = self.summary.lock().unwrap();
+        f(summary.get_or_insert_with(|| CellSummary::new(self.size, &self.cells)))
+    }
+
+    /// Whether any cell may be partially see-through. Palettes are checked
+    /// instead of the cells using them, so unused entries count too.
+    pub(crate) fn is_translucent(&self, types: &VoxelTypeRegistry) -> bool {
+        match &self.cells {
+            VoxelCells::Color(_) => self.with_summary(|summary| summary.translucent_cells > 0),
+            VoxelCells::Indexed8 { palette, .. } | VoxelCells::Indexed16 { palette, .. } => {
+                palette.iter().any(|&color| is_translucent_color(color))
+            }
+            VoxelCells::Typed(_) => {
+                let translucency = types.translucency();
+                self.with_summary(|summary| {
+                    summary.type_counts.iter().enumerate().any(|(id, &count)| {
+                        count > 0 && translucency.get(id).copied().unwrap_or(false)
+                    })
+                })
+            }
+        }
+    }
+
+    /// Words of the occupancy pyramid of a grid of `size`, see
+    /// [`VoxelData::occupancy`]
+    pub(crate) fn occupancy_words(size: UVec3) -> usize {
+        OCCUPANCY_BRICKS
+            .iter()
+            .map(|&brick| (Self::volume((size + brick - 1) / brick) + 31) / 32)
+            .sum()
+    }
+
+    /// One bit per brick of [`OCCUPANCY_BRICKS`] cells, set if any cell in it
+    /// is occupied. Bricks are laid out like cells, each level starts on a
+    /// new word.
+    pub(crate) fn occupancy(&self) -> Vec<u32> {
+        self.occupancy_range(0..Self::occupancy_words(self.size))
+    }
+
+    /// Words `words` of [`VoxelData::occupancy`]
+    pub(crate) fn occupancy_range(&self, words: Range<usize>) -> Vec<u32> {
+        self.with_summary(|summary| summary.occupancy[words].to_vec())
+    }
+
+    /// Takes the regions edited since the last call
+    pub(crate) fn take_dirty(&self) -> DirtyRegion {
+        std::mem::take(&mut *self.dirty.lock().unwrap())
+    }
+
+    fn volume(size: UVec3) -> usize {
+        size.to_array().iter().map(|&axis| axis as usize).product()
+    }
+
+    /// Volume of a new grid, checking its size is supported
+    fn checked_volume(size: UVec3) -> usize {
+        assert!(
+            size.max_element() <= MAX_SIZE,
+            "voxel grids can be at most {} cells wide",
+            MAX_SIZE
+        );
+        let volume = size.x as u64 * size.y as u64 * size.z as u64;
+        assert!(
+            volume < 1 << 32,
+            "voxel grids must have fewer than 2³² cells"
+        );
+        volume as usize
     }
 }
 
-impl RenderAsset for VoxelData {
-    type ExtractedAsset = Self;
-    type PreparedAsset = VoxelMeta;
+impl CellSummary {
+    fn new(size: UVec3, cells: &VoxelCells) -> Self {
+        let mut summary = Self {
+            occupancy: vec![0; VoxelData::occupancy_words(size)],
+            translucent_cells: 0,
+            type_counts: Vec::new(),
+        };
+        match cells {
+            VoxelCells::Typed(ids) => ids.iter().for_each(|&id| summary.count_type(id, 1)),
+            _ => {
+                summary.translucent_cells = (0..cells.len())
+                    .filter(|&index| is_translucent_color(cells.color(index)))
+                    .count()
+            }
+        }
 
-    type Param = (SRes<RenderDevice>, SRes<pipeline::VoxelPipeline>);
-    fn extract_asset(&self) -> Self::ExtractedAsset {
-        self.clone()
+        let mut start = 0;
+        for brick in OCCUPANCY_BRICKS {
+            let bricks = (size + brick - 1) / brick;
+            let mut index = 0;
+            for y in 0..size.y {
+                for z in 0..size.z {
+                    for x in 0..size.x {
+                        if cells.is_occupied(index) {
+                            let at = UVec3::new(x, y, z) / brick;
+                            let bit =
+                                (at.x + at.z * bricks.x + at.y * bricks.x * bricks.z) as usize;
+                            summary.occupancy[start + bit / 32] |= 1 << (bit % 32);
+                        }
+                        index += 1;
+                    }
+                }
+            }
+            start += (VoxelData::volume(bricks) + 31) / 32;
+        }
+        summary
     }
 
-    fn prepare_asset(
-        extracted_asset: Self::ExtractedAsset,
-        (render_device, pipeline): &mut SystemParamItem<Self::Param>,
-    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
-        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("voxel data buffer"),
-            contents: cast_slice(&extracted_asset.0),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
+    /// Adds `delta` cells of type `id`, air isn't counted
+    fn count_type(&mut self, id: u16, delta: i32) {
+        if id == VoxelTypeId::AIR.0 {
+            return;
+        }
+        let id = id as usize;
+        if self.type_counts.len() <= id {
+            self.type_counts.resize(id + 1, 0);
+        }
+        self.type_counts[id] = (self.type_counts[id] as i32 + delta) as u32;
+    }
+}
 
-        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            label: Some("voxel data bind group"),
-            layout: &pipeline.voxel_data_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: buffer.as_entire_binding(),
-            }],
-        });
+fn is_translucent_color(color: u32) -> bool {
+    matches!(color >> 24, 1..=254)
+}
+
+impl VoxelCells {
+    /// ARGB color of the cell at `index` in memory layout order, indices
+    /// past the end of the palette and typed cells read as transparent
+    pub fn color(&self, index: usize) -> u32 {
+        match self {
+            VoxelCells::Typed(_) => 0,
+            VoxelCells::Color(colors) => colors[index],
+            VoxelCells::Indexed8 { indices, palette } => {
+                palette.get(indices[index] as usize).copied().unwrap_or(0)
+            }
+            VoxelCells::Indexed16 { indices, palette } => {
+                palette.get(indices[index] as usize).copied().unwrap_or(0)
+            }
+        }
+    }
 
-        Ok(VoxelMeta {
-            _buffer: buffer,
-            bind_group,
+    /// Whether the cell at `index` in memory layout order is not empty, that
+    /// is it has non-zero alpha or a type other than air
+    pub fn is_occupied(&self, index: usize) -> bool {
+        match self {
+            VoxelCells::Typed(ids) => ids[index] != VoxelTypeId::AIR.0,
+            _ => self.color(index) >> 24 != 0,
+        }
+    }
+
+    /// Whether any cell in the `min..max` box of a grid of `size` is occupied,
+    /// the box is clipped to the grid
+    fn any_occupied(&self, size: UVec3, min: UVec3, max: UVec3) -> bool {
+        let max = max.min(size);
+        (min.y..max.y).any(|y| {
+            (min.z..max.z).any(|z| {
+                (min.x..max.x)
+                    .any(|x| self.is_occupied((x + z * size.x + y * size.x * size.z) as usize))
+            })
         })
     }
+
+    fn set(&mut self, index: usize, color: u32) -> Result<(), VoxelDataError> {
+        match self {
+            VoxelCells::Color(colors) => colors[index] = color,
+            VoxelCells::Indexed8 { indices, palette } => {
+                match palette.iter().position(|&entry| entry == color) {
+                    Some(entry) if entry < 256 => indices[index] = entry as u8,
+                    _ if palette.len() < 256 => {
+                        indices[index] = palette.len() as u8;
+                        palette.push(color);
+                    }
+                    _ => {
+                        *self = VoxelCells::Indexed16 {
+                            indices: indices.iter().map(|&index| index as u16).collect(),
+                            palette: std::mem::take(palette),
+                        };
+                        return self.set(index, color);
+                    }
+                }
+            }
+            VoxelCells::Indexed16 { indices, palette } => {
+                match palette.iter().position(|&entry| entry == color) {
+                    Some(entry) if entry < 65536 => indices[index] = entry as u16,
+                    _ if palette.len() < 65536 => {
+                        indices[index] = palette.len() as u16;
+                        palette.push(color);
+                    }
+                    _ => return Err(VoxelDataError::PaletteFull(color)),
+                }
+            }
+            VoxelCells::Typed(_) => return Err(VoxelDataError::FormatMismatch),
+        }
+        Ok(())
+    }
+
+    /// Format code understood by `voxel.wgsl`
+    pub(crate) fn format(&self) -> u32 {
+        match self {
+            VoxelCells::Color(_) => 0,
+            VoxelCells::Indexed8 { .. } => 1,
+            VoxelCells::Indexed16 { .. } => 2,
+            VoxelCells::Typed(_) => 3,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            VoxelCells::Color(colors) => colors.len(),
+            VoxelCells::Indexed8 { indices, .. } => indices.len(),
+            VoxelCells::Indexed16 { indices, .. } => indices.len(),
+            VoxelCells::Typed(types) => types.len(),
+        }
+    }
+
+    /// Range of packed words holding the given cells
+    pub(crate) fn word_range(&self, cells: Range<usize>) -> Range<usize> {
+        let per_word = match self {
+            VoxelCells::Color(_) => 1,
+            VoxelCells::Indexed8 { .. } => 4,
+            VoxelCells::Indexed16 { .. } | VoxelCells::Typed(_) => 2,
+        };
+        cells.start / per_word..(cells.end + per_word - 1) / per_word
+    }
+
+    /// Cells packed into 32-bit words, indices are packed little-endian
+    pub(crate) fn words(&self) -> Vec<u32> {
+        self.packed_words(self.word_range(0..self.len()))
+    }
+
+    /// Packed words in `words`, see [`VoxelCells::words`]
+    pub(crate) fn packed_words(&self, words: Range<usize>) -> Vec<u32> {
+        match self {
+            VoxelCells::Color(colors) => colors[words].to_vec(),
+            VoxelCells::Indexed8 { indices, .. } => indices
+                [words.start * 4..(words.end * 4).min(indices.len())]
+                .chunks(4)
+                .map(|chunk| {
+                    chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0, |word, (i, &index)| word | (index as u32) << (i * 8))
+                })
+                .collect(),
+            VoxelCells::Indexed16 { indices, .. } | VoxelCells::Typed(indices) => indices
+                [words.start * 2..(words.end * 2).min(indices.len())]
+                .chunks(2)
+                .map(|chunk| {
+                    chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0, |word, (i, &index)| word | (index as u32) << (i * 16))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl DirtyRegion {
+    fn mark(&mut self, cells: Range<usize>) {
+        Self::merge(&mut self.cells, cells);
+    }
+
+    fn mark_occupancy(&mut self, words: Range<usize>) {
+        Self::merge(&mut self.occupancy, words);
+    }
+
+    fn merge(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+        if let Some(last) = ranges.last_mut() {
+            if range.start <= last.end && last.start <= range.end {
+                *last = last.start.min(range.start)..last.end.max(range.end);
+                return;
+            }
+        }
+        if ranges.len() == MAX_DIRTY_RANGES {
+            let start = ranges.iter().map(|r| r.start).min().unwrap();
+            let end = ranges.iter().map(|r| r.end).max().unwrap();
+            *ranges = vec![start.min(range.start)..end.max(range.end)];
+        } else {
+            ranges.push(range);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.occupancy.is_empty() && !self.palette && !self.rebuild
+    }
+}
+
+impl Clone for VoxelData {
+    fn clone(&self) -> Self {
+        Self {
+            size: self.size,
+            cells: self.cells.clone(),
+            dirty: Default::default(),
+            bounds: Mutex::new(*self.bounds.lock().unwrap()),
+            summary: Mutex::new(self.summary.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl Default for VoxelData {
+    fn default() -> Self {
+        Self::new(UVec3::splat(16))
+    }
 }
 
 #[derive(Component, Clone, Default)]
@@ -75,9 +744,221 @@ pub struct Voxel {
 
 impl ExtractComponent for Voxel {
     type Query = &'static Self;
-    type Filter = ();
+    /// Meshed voxels are drawn by bevy's PBR pipeline instead
+    type Filter = Without<Handle<Mesh>>;
 
     fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Self {
         item.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{VoxelCells, VoxelData, VoxelDataError, MAX_DIRTY_RANGES};
+    use crate::{VoxelType, VoxelTypeId, VoxelTypeRegistry};
+
+    const RED: u32 = 0xFFFF_0000;
+    const GREEN: u32 = 0xFF00_FF00;
+
+    #[test]
+    fn rejects_positions_outside_the_grid() {
+        let size = UVec3::new(4, 3, 2);
+        let mut data = VoxelData::new(size);
+        let position = UVec3::new(1, 3, 1);
+        let error = VoxelDataError::OutOfBounds { position, size };
+        assert_eq!(data.index(position), Err(error.clone()));
+        assert_eq!(data.get(position), Err(error.clone()));
+        assert_eq!(data.set(position, RED), Err(error));
+        assert!(data
+            .fill_box(UVec3::ZERO, UVec3::new(4, 3, 3), RED)
+            .is_err());
+        assert!(data.take_dirty().is_empty());
+        assert_eq!(data.index(UVec3::new(3, 2, 1)), Ok(3 + 4 + 2 * 4 * 2));
+    }
+
+    #[test]
+    fn rejects_mismatched_formats() {
+        let mut colors = VoxelData::new(UVec3::splat(2));
+        let mut types = VoxelData::with_types(UVec3::splat(2));
+        let id = VoxelTypeId(1);
+        assert_eq!(
+            colors.set_type(UVec3::ZERO, id),
+            Err(VoxelDataError::FormatMismatch)
+        );
+        assert_eq!(
+            colors.get_type(UVec3::ZERO),
+            Err(VoxelDataError::FormatMismatch)
+        );
+        assert_eq!(
+            types.set(UVec3::ZERO, RED),
+            Err(VoxelDataError::FormatMismatch)
+        );
+        assert_eq!(types.get(UVec3::ZERO), Err(VoxelDataError::FormatMismatch));
+
+        types.set_type(UVec3::ONE, id).unwrap();
+        assert_eq!(types.get_type(UVec3::ONE), Ok(id));
+        assert_eq!(types.iter_types().collect::<Vec<_>>(), [(UVec3::ONE, id)]);
+        assert_eq!(types.iter().count(), 0);
+    }
+
+    #[test]
+    fn widens_full_8_bit_palettes() {
+        let mut data = VoxelData::with_palette(UVec3::new(16, 1, 17), vec![0]);
+        for i in 0..255 {
+            data.set(data.position(i), 0xFF00_0000 | i as u32).unwrap();
+        }
+        assert!(matches!(data.cells(), VoxelCells::Indexed8 { .. }));
+
+        data.set(data.position(255), 0xFF00_0000 | 255).unwrap();
+        assert!(matches!(data.cells(), VoxelCells::Indexed16 { .. }));
+        // Buffer sizes changed
+        assert!(data.take_dirty().rebuild);
+        for i in 0..256 {
+            assert_eq!(data.get(data.position(i)), Ok(0xFF00_0000 | i as u32));
+        }
+    }
+
+    #[test]
+    fn reports_full_palettes() {
+        let palette = (0..65536).map(|i| 0xFF00_0000 | i).collect();
+        let mut data = VoxelData::with_palette(UVec3::splat(2), palette);
+        data.set(UVec3::ZERO, 0xFF00_FFFF).unwrap();
+        assert_eq!(
+            data.set(UVec3::ONE, RED),
+            Err(VoxelDataError::PaletteFull(RED))
+        );
+        assert_eq!(data.get(UVec3::ONE), Ok(0xFF00_0000));
+    }
+
+    #[test]
+    fn palette_mut_only_marks_indexed_grids() {
+        let mut data = VoxelData::new(UVec3::splat(2));
+        assert!(data.palette_mut().is_none());
+        assert!(data.take_dirty().is_empty());
+
+        let mut data = VoxelData::with_palette(UVec3::splat(2), vec![0, RED]);
+        data.palette_mut().unwrap()[1] = GREEN;
+        assert!(data.take_dirty().palette);
+    }
+
+    #[test]
+    fn merges_dirty_ranges() {
+        let mut data = VoxelData::new(UVec3::new(256, 1, 1));
+        data.set(UVec3::ZERO, RED).unwrap();
+        data.set(UVec3::X, RED).unwrap();
+        assert_eq!(data.take_dirty().cells, [0..2]);
+
+        // Every other cell, so no range touches the previous one
+        for i in 0..MAX_DIRTY_RANGES as u32 {
+            data.set(UVec3::new(i * 2, 0, 0), GREEN).unwrap();
+        }
+        assert_eq!(data.take_dirty().cells.len(), MAX_DIRTY_RANGES);
+
+        for i in 0..=MAX_DIRTY_RANGES as u32 {
+            data.set(UVec3::new(i * 2, 0, 0), RED).unwrap();
+        }
+        let end = MAX_DIRTY_RANGES * 2 + 1;
+        assert_eq!(data.take_dirty().cells, [0..end]);
+    }
+
+    #[test]
+    fn fills_boxes() {
+        let mut data = VoxelData::new(UVec3::splat(4));
+        data.fill_box(UVec3::ONE, UVec3::new(3, 2, 4), RED).unwrap();
+        assert_eq!(data.iter().count(), 6);
+        assert!(data.iter().all(|(_, color)| color == RED));
+        assert_eq!(data.bounds(), Some((UVec3::ONE, UVec3::new(3, 2, 4))));
+    }
+
+    #[test]
+    fn keeps_bounds_through_edits() {
+        let mut data = VoxelData::new(UVec3::splat(8));
+        assert_eq!(data.bounds(), None);
+        data.set(UVec3::new(1, 2, 3), RED).unwrap();
+        data.set(UVec3::new(5, 4, 3), RED).unwrap();
+        assert_eq!(
+            data.bounds(),
+            Some((UVec3::new(1, 2, 3), UVec3::new(6, 5, 4)))
+        );
+
+        // Emptying a cell on the border shrinks the box
+        data.set(UVec3::new(5, 4, 3), 0).unwrap();
+        assert_eq!(
+            data.bounds(),
+            Some((UVec3::new(1, 2, 3), UVec3::new(2, 3, 4)))
+        );
+
+        data.clear();
+        assert_eq!(data.bounds(), None);
+        data.set(UVec3::splat(7), GREEN).unwrap();
+        assert_eq!(data.bounds(), Some((UVec3::splat(7), UVec3::splat(8))));
+
+        // Cleared indexed cells point to the first palette entry
+        let mut data = VoxelData::with_palette(UVec3::splat(2), vec![RED]);
+        data.clear();
+        assert_eq!(data.bounds(), Some((UVec3::ZERO, UVec3::splat(2))));
+    }
+
+    #[test]
+    fn keeps_summaries_in_line_with_rebuilds() {
+        let mut types = VoxelTypeRegistry::default();
+        types.register(VoxelType::default());
+        types.register(VoxelType {
+            base_color: Color::rgba(1.0, 1.0, 1.0, 0.5),
+            ..default()
+        });
+
+        let mut seed = 0x2545_f491_u32;
+        let mut random = move |range: u32| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed % range
+        };
+
+        // Sizes that aren't multiples of the bricks, with most cells empty
+        let size = UVec3::new(9, 5, 7);
+        for mut data in [VoxelData::new(size), VoxelData::with_types(size)] {
+            // Edits only update summaries that were built
+            data.occupancy();
+            data.bounds();
+            for step in 0..4000 {
+                let position = UVec3::new(random(size.x), random(size.y), random(size.z));
+                let value = random(6);
+                if matches!(data.cells(), VoxelCells::Typed(_)) {
+                    let id = VoxelTypeId(value.saturating_sub(3) as u16);
+                    data.set_type(position, id).unwrap();
+                } else {
+                    let color = [0, 0, 0, 0x0000_00FF, 0x80FF_0000, RED][value as usize];
+                    data.set(position, color).unwrap();
+                }
+
+                if step % 100 == 99 {
+                    let rebuilt = VoxelData::from_cells(size, data.cells().clone());
+                    assert_eq!(data.occupancy(), rebuilt.occupancy(), "step {}", step);
+                    assert_eq!(data.bounds(), rebuilt.bounds(), "step {}", step);
+                    assert_eq!(
+                        data.is_translucent(&types),
+                        rebuilt.is_translucent(&types),
+                        "step {}",
+                        step
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 65535 cells wide")]
+    fn rejects_oversized_grids() {
+        VoxelData::new(UVec3::new(1 << 16, 1, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "fewer than 2³² cells")]
+    fn rejects_grids_with_too_many_cells() {
+        VoxelData::with_types(UVec3::new(65535, 65535, 2));
+    }
+}
@@ -0,0 +1,125 @@
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use bytemuck::{Pod, Zeroable};
+
+/// Id of a type registered in [`VoxelTypeRegistry`], stored in the cells of
+/// grids created with [`VoxelData::with_types`](crate::VoxelData::with_types)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VoxelTypeId(pub u16);
+
+impl VoxelTypeId {
+    /// Empty cell, never registered
+    pub const AIR: Self = Self(0);
+}
+
+/// Material and physical properties shared by every cell of a type
+#[derive(Clone, Debug)]
+pub struct VoxelType {
+    pub name: String,
+    /// Alpha below one makes the type transparent
+    pub base_color: Color,
+    /// Light given off by the type, added to the lit color before tone
+    /// mapping. There is no HDR target or bloom, so bright colors saturate
+    /// instead of glowing. Surrounding geometry is only lit through
+    /// [`VoxelEmissiveLights`](crate::VoxelEmissiveLights), which only
+    /// supports typed grids.
+    pub emissive: Color,
+    pub perceptual_roughness: f32,
+    pub metallic: f32,
+    /// Whether the type blocks movement, non-solid types are only visual
+    pub solid: bool,
+}
+
+impl VoxelType {
+    /// Whether cells of this type are partially see-through
+    pub fn is_translucent(&self) -> bool {
+        let alpha = self.base_color.a();
+        alpha > 0.0 && alpha < 1.0
+    }
+}
+
+impl Default for VoxelType {
+    fn default() -> Self {
+        // Matches the defaults of `StandardMaterial`
+        Self {
+            name: String::new(),
+            base_color: Color::WHITE,
+            emissive: Color::BLACK,
+            perceptual_roughness: 0.089,
+            metallic: 0.01,
+            solid: true,
+        }
+    }
+}
+
+/// Block types available to typed grids, uploaded to the GPU as a table
+/// indexed by [`VoxelTypeId`]
+#[derive(Clone, Default, ExtractResource)]
+pub struct VoxelTypeRegistry {
+    /// Type of id `i` is at `i - 1`
+    types: Vec<VoxelType>,
+}
+
+/// Row of the type table read by `voxel.wgsl`
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub(crate) struct GpuVoxelType {
+    base_color: [f32; 4],
+    emissive: [f32; 4],
+    perceptual_roughness: f32,
+    metallic: f32,
+    _padding: [u32; 2],
+}
+
+impl VoxelTypeRegistry {
+    /// Adds a type and returns its id
+    ///
+    /// # Panics
+    ///
+    /// Panics if 65535 types are already registered
+    pub fn register(&mut self, voxel_type: VoxelType) -> VoxelTypeId {
+        let id = u16::try_from(self.types.len() + 1).expect("too many voxel types registered");
+        self.types.push(voxel_type);
+        VoxelTypeId(id)
+    }
+
+    /// Type registered under `id`, `None` for [`VoxelTypeId::AIR`]
+    pub fn get(&self, id: VoxelTypeId) -> Option<&VoxelType> {
+        self.types.get((id.0 as usize).checked_sub(1)?)
+    }
+
+    /// Mutable type registered under `id`, edits apply to every grid using it
+    pub fn get_mut(&mut self, id: VoxelTypeId) -> Option<&mut VoxelType> {
+        self.types.get_mut((id.0 as usize).checked_sub(1)?)
+    }
+
+    /// Id of the first type registered with `name`
+    pub fn id(&self, name: &str) -> Option<VoxelTypeId> {
+        let index = self.types.iter().position(|ty| ty.name == name)?;
+        Some(VoxelTypeId(index as u16 + 1))
+    }
+
+    /// Iterates over registered ids and types
+    pub fn iter(&self) -> impl Iterator<Item = (VoxelTypeId, &VoxelType)> {
+        (1..).map(VoxelTypeId).zip(&self.types)
+    }
+
+    /// Translucency of every id, the first entry stands for air
+    pub(crate) fn translucency(&self) -> Vec<bool> {
+        std::iter::once(false)
+            .chain(self.types.iter().map(VoxelType::is_translucent))
+            .collect()
+    }
+
+    /// Table indexed by id, the first row stands for air
+    pub(crate) fn gpu_table(&self) -> Vec<GpuVoxelType> {
+        std::iter::once(GpuVoxelType::zeroed())
+            .chain(self.types.iter().map(|ty| GpuVoxelType {
+                base_color: ty.base_color.as_linear_rgba_f32(),
+                emissive: ty.emissive.as_linear_rgba_f32(),
+                perceptual_roughness: ty.perceptual_roughness,
+                metallic: ty.metallic,
+                _padding: [0; 2],
+            }))
+            .collect()
+    }
+}
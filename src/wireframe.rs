@@ -9,7 +9,7 @@ use bevy::asset::load_internal_asset;
 use bevy::core_pipeline::core_3d::Opaque3d;
 use bevy::ecs::{prelude::*, reflect::ReflectComponent};
 use bevy::pbr::MeshPipeline;
-use bevy::pbr::{MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup};
+use bevy::pbr::{MeshPipelineKey, MeshUniform, SetMeshViewBindGroup};
 use bevy::prelude::*;
 use bevy::reflect::{Reflect, TypeUuid};
 use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
@@ -28,7 +28,12 @@ use bevy::render::{
 };
 use bevy::utils::tracing::error;
 
-use super::{draw, voxel, voxel_mesh};
+use super::{
+    draw,
+    instance::{VoxelInstance, VoxelInstances},
+    storage::VoxelStorage,
+    voxel, voxel_mesh,
+};
 
 pub const WIREFRAME_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 6379866545205140404);
@@ -102,6 +107,11 @@ impl SpecializedMeshPipeline for WireframePipeline {
         let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
         descriptor.vertex.shader = self.shader.clone_weak();
         descriptor.fragment.as_mut().unwrap().shader = self.shader.clone_weak();
+        descriptor
+            .vertex
+            .buffers
+            .push(VoxelInstance::buffer_layout());
+        descriptor.layout = Some(vec![self.mesh_pipeline.view_layout.clone()]);
         descriptor.primitive.polygon_mode = PolygonMode::Line;
         descriptor.depth_stencil.as_mut().unwrap().bias.slope_scale = 1.0;
         Ok(descriptor)
@@ -111,6 +121,7 @@ impl SpecializedMeshPipeline for WireframePipeline {
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::type_complexity)]
 fn queue_wireframes(
+    mut commands: Commands,
     opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
     render_meshes: Res<RenderAssets<Mesh>>,
     wireframe_config: Res<VoxelWireframeConfig>,
@@ -119,9 +130,11 @@ fn queue_wireframes(
     mut pipeline_cache: ResMut<PipelineCache>,
     msaa: Res<Msaa>,
     voxel_mesh: Res<voxel_mesh::VoxelMesh>,
+    storage: Res<VoxelStorage>,
+    mut instances: ResMut<VoxelInstances>,
     mut material_meshes: ParamSet<(
-        Query<(Entity, &MeshUniform), With<voxel::Voxel>>,
-        Query<(Entity, &MeshUniform), (With<voxel::Voxel>, With<VoxelWireframe>)>,
+        Query<(&voxel::Voxel, &MeshUniform)>,
+        Query<(&voxel::Voxel, &MeshUniform), With<VoxelWireframe>>,
     )>,
     mut views: Query<(&ExtractedView, &VisibleEntities, &mut RenderPhase<Opaque3d>)>,
 ) {
@@ -130,49 +143,48 @@ fn queue_wireframes(
         .get_id::<DrawWireframes>()
         .unwrap();
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples);
-    for (view, visible_entities, mut opaque_phase) in views.iter_mut() {
-        let rangefinder = view.rangefinder3d();
 
-        let add_render_phase = |(entity, mesh_uniform): (Entity, &MeshUniform)| {
-            if let Some(mesh) = render_meshes.get(&voxel_mesh.mesh) {
-                let key =
-                    msaa_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
-                let pipeline_id = pipelines.specialize(
-                    &mut pipeline_cache,
-                    &wireframe_pipeline,
-                    key,
-                    &mesh.layout,
-                );
-                let pipeline_id = match pipeline_id {
-                    Ok(id) => id,
-                    Err(err) => {
-                        error!("{}", err);
-                        return;
-                    }
-                };
-                opaque_phase.add(Opaque3d {
-                    entity,
-                    pipeline: pipeline_id,
-                    draw_function: draw_custom,
-                    distance: rangefinder.distance(&mesh_uniform.transform),
-                });
+    let mesh = match render_meshes.get(&voxel_mesh.mesh) {
+        Some(mesh) => mesh,
+        None => return,
+    };
+    let key = msaa_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+    let pipeline_id =
+        match pipelines.specialize(&mut pipeline_cache, &wireframe_pipeline, key, &mesh.layout) {
+            Ok(id) => id,
+            Err(err) => {
+                error!("{}", err);
+                return;
             }
         };
 
-        if wireframe_config.global {
+    for (view, visible_entities, mut opaque_phase) in views.iter_mut() {
+        let rangefinder = view.rangefinder3d();
+        let distance = |transform: &Mat4| rangefinder.distance(transform);
+
+        let batches = if wireframe_config.global {
             let query = material_meshes.p0();
-            visible_entities
+            let visible = visible_entities
                 .entities
                 .iter()
-                .filter_map(|visible_entity| query.get(*visible_entity).ok())
-                .for_each(add_render_phase);
+                .filter_map(|visible_entity| query.get(*visible_entity).ok());
+            instances.push_batches(&mut commands, &storage, distance, false, visible)
         } else {
             let query = material_meshes.p1();
-            visible_entities
+            let visible = visible_entities
                 .entities
                 .iter()
-                .filter_map(|visible_entity| query.get(*visible_entity).ok())
-                .for_each(add_render_phase);
+                .filter_map(|visible_entity| query.get(*visible_entity).ok());
+            instances.push_batches(&mut commands, &storage, distance, false, visible)
+        };
+
+        for (entity, distance) in batches {
+            opaque_phase.add(Opaque3d {
+                entity,
+                pipeline: pipeline_id,
+                draw_function: draw_custom,
+                distance,
+            });
         }
     }
 }
@@ -180,6 +192,5 @@ fn queue_wireframes(
 type DrawWireframes = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
-    SetMeshBindGroup<1>,
-    draw::DrawVoxel,
+    draw::DrawVoxelBatch,
 );
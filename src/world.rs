@@ -0,0 +1,265 @@
+use bevy::{asset::HandleId, prelude::*, utils::HashMap};
+
+use super::{
+    bundle::VoxelBundle,
+    meshing::VoxelRenderMode,
+    voxel::{Voxel, VoxelData, VoxelDataError},
+    voxel_type::VoxelTypeId,
+};
+
+/// Edge of the cubic chunks of a [`VoxelWorld`], in cells
+pub const CHUNK_SIZE: u32 = 16;
+
+/// Unbounded voxel grid split into [`CHUNK_SIZE`]³ chunks stored as
+/// [`VoxelData`] assets.
+///
+/// Positions are in cells relative to the entity holding the world, one cell
+/// per world unit. Every chunk with occupied cells is drawn by a child
+/// [`VoxelBundle`], so the entity needs a transform and visibility, e.g. a
+/// [`SpatialBundle`].
+#[derive(Component)]
+pub struct VoxelWorld {
+    /// Whether chunks created on demand hold [`VoxelTypeId`]s instead of colors
+    typed: bool,
+    /// Render mode given to chunk entities spawned from now on
+    pub render_mode: VoxelRenderMode,
+    chunks: HashMap<IVec3, Chunk>,
+    /// Chunk of each asset, to map asset events back to chunks
+    coords: HashMap<HandleId, IVec3>,
+    /// Chunks whose entity has to be spawned or despawned
+    pending: Vec<IVec3>,
+    /// Entities of removed chunks
+    despawned: Vec<Entity>,
+}
+
+struct Chunk {
+    data: Handle<VoxelData>,
+    entity: Option<Entity>,
+}
+
+impl Default for VoxelWorld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VoxelWorld {
+    /// Creates an empty world whose chunks store colors
+    pub fn new() -> Self {
+        Self {
+            typed: false,
+            render_mode: default(),
+            chunks: default(),
+            coords: default(),
+            pending: default(),
+            despawned: default(),
+        }
+    }
+
+    /// Creates an empty world whose chunks store voxel types, see
+    /// [`VoxelData::with_types`]
+    pub fn with_types() -> Self {
+        Self {
+            typed: true,
+            ..Self::new()
+        }
+    }
+
+    /// Chunk coordinates holding `position`, and the position within the chunk
+    pub fn chunk_position(position: IVec3) -> (IVec3, UVec3) {
+        let size = CHUNK_SIZE as i32;
+        (
+            position.div_euclid(IVec3::splat(size)),
+            position.rem_euclid(IVec3::splat(size)).as_uvec3(),
+        )
+    }
+
+    /// Data of the chunk at `coords`, if it exists
+    pub fn chunk(&self, coords: IVec3) -> Option<&Handle<VoxelData>> {
+        self.chunks.get(&coords).map(|chunk| &chunk.data)
+    }
+
+    /// Entity drawing the chunk at `coords`, spawned once it has occupied cells
+    pub fn chunk_entity(&self, coords: IVec3) -> Option<Entity> {
+        self.chunks.get(&coords)?.entity
+    }
+
+    /// Iterates over coordinates and data of every chunk
+    pub fn chunks(&self) -> impl Iterator<Item = (IVec3, &Handle<VoxelData>)> {
+        self.chunks
+            .iter()
+            .map(|(coords, chunk)| (*coords, &chunk.data))
+    }
+
+    /// Sets the data of the chunk at `coords`, replacing any previous chunk
+    pub fn insert_chunk(&mut self, coords: IVec3, data: Handle<VoxelData>) {
+        self.remove_chunk(coords);
+        self.coords.insert(data.id, coords);
+        self.chunks.insert(coords, Chunk { data, entity: None });
+        self.pending.push(coords);
+    }
+
+    /// Removes the chunk at `coords` and despawns its entity, the asset is
+    /// freed once no other handle to it is left
+    pub fn remove_chunk(&mut self, coords: IVec3) -> Option<Handle<VoxelData>> {
+        let chunk = self.chunks.remove(&coords)?;
+        self.coords.remove(&chunk.data.id);
+        self.despawned.extend(chunk.entity);
+        Some(chunk.data)
+    }
+
+    /// ARGB color of the cell at `position`, cells of missing or unloaded
+    /// chunks and of typed worlds read as transparent
+    pub fn get_voxel(&self, assets: &Assets<VoxelData>, position: IVec3) -> u32 {
+        let (coords, local) = Self::chunk_position(position);
+        self.chunk(coords)
+            .and_then(|handle| assets.get(handle))
+            .and_then(|data| data.get(local).ok())
+            .unwrap_or(0)
+    }
+
+    /// Sets the ARGB color of the cell at `position`, creating its chunk if
+    /// needed, typed worlds are rejected
+    pub fn set_voxel(
+        &mut self,
+        assets: &mut Assets<VoxelData>,
+        position: IVec3,
+        color: u32,
+    ) -> Result<(), VoxelDataError> {
+        if self.typed {
+            return Err(VoxelDataError::FormatMismatch);
+        }
+        if color >> 24 == 0 && self.get_voxel(assets, position) == 0 {
+            return Ok(());
+        }
+        let (coords, local) = Self::chunk_position(position);
+        self.chunk_data_mut(assets, coords).set(local, color)
+    }
+
+    /// Type of the cell at `position`, cells of missing or unloaded chunks
+    /// read as [`VoxelTypeId::AIR`]
+    pub fn get_type(&self, assets: &Assets<VoxelData>, position: IVec3) -> VoxelTypeId {
+        let (coords, local) = Self::chunk_position(position);
+        self.chunk(coords)
+            .and_then(|handle| assets.get(handle))
+            .and_then(|data| data.get_type(local).ok())
+            .unwrap_or(VoxelTypeId::AIR)
+    }
+
+    /// Sets the type of the cell at `position`, creating its chunk if needed,
+    /// color worlds are rejected
+    pub fn set_type(
+        &mut self,
+        assets: &mut Assets<VoxelData>,
+        position: IVec3,
+        id: VoxelTypeId,
+    ) -> Result<(), VoxelDataError> {
+        if !self.typed {
+            return Err(VoxelDataError::FormatMismatch);
+        }
+        if id == VoxelTypeId::AIR && self.get_type(assets, position) == VoxelTypeId::AIR {
+            return Ok(());
+        }
+        let (coords, local) = Self::chunk_position(position);
+        self.chunk_data_mut(assets, coords).set_type(local, id)
+    }
+
+    /// Replaces the chunk at `coords` with an empty one in the format of the
+    /// world
+    pub fn create_chunk(
+        &mut self,
+        assets: &mut Assets<VoxelData>,
+        coords: IVec3,
+    ) -> Handle<VoxelData> {
+        let size = UVec3::splat(CHUNK_SIZE);
+        let data = if self.typed {
+            VoxelData::with_types(size)
+        } else {
+            VoxelData::new(size)
+        };
+        let handle = assets.add(data);
+        self.insert_chunk(coords, handle.clone());
+        handle
+    }
+
+    fn chunk_data_mut<'a>(
+        &mut self,
+        assets: &'a mut Assets<VoxelData>,
+        coords: IVec3,
+    ) -> &'a mut VoxelData {
+        let handle = match self.chunk(coords) {
+            Some(handle) if assets.contains(handle) => handle.clone_weak(),
+            _ => self.create_chunk(assets, coords),
+        };
+        assets.get_mut(&handle).unwrap()
+    }
+}
+
+/// Transform placing a chunk's proxy over its cells
+fn chunk_transform(coords: IVec3) -> Transform {
+    let size = CHUNK_SIZE as f32;
+    Transform::from_translation((coords.as_vec3() + 0.5) * size).with_scale(Vec3::splat(size))
+}
+
+/// Spawns a child [`VoxelBundle`] for every chunk that gained occupied cells
+/// and despawns those of removed or emptied chunks
+pub(crate) fn update_world_chunks(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<VoxelData>>,
+    assets: Res<Assets<VoxelData>>,
+    mut worlds: Query<(Entity, &mut VoxelWorld)>,
+) {
+    let changed: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle.id),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect();
+
+    for (world_entity, mut world) in worlds.iter_mut() {
+        let world = &mut *world;
+        for entity in world.despawned.drain(..) {
+            commands.entity(entity).despawn_recursive();
+        }
+
+        let mut pending = std::mem::take(&mut world.pending);
+        pending.extend(
+            changed
+                .iter()
+                .filter_map(|id| world.coords.get(id).copied()),
+        );
+        for coords in pending {
+            let chunk = match world.chunks.get_mut(&coords) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            // Unloaded chunks are checked again once their asset is created
+            let occupied = match assets.get(&chunk.data) {
+                Some(data) => data.bounds().is_some(),
+                None => continue,
+            };
+            match (chunk.entity, occupied) {
+                (None, true) => {
+                    let entity = commands
+                        .spawn_bundle(VoxelBundle {
+                            voxel: Voxel {
+                                data: chunk.data.clone(),
+                            },
+                            render_mode: world.render_mode,
+                            transform: chunk_transform(coords),
+                            ..default()
+                        })
+                        .id();
+                    commands.entity(world_entity).add_child(entity);
+                    chunk.entity = Some(entity);
+                }
+                (Some(entity), false) => {
+                    commands.entity(entity).despawn_recursive();
+                    chunk.entity = None;
+                }
+                _ => {}
+            }
+        }
+    }
+}